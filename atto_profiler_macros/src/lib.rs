@@ -0,0 +1,80 @@
+
+// Attribute macros for the atto profiler, re-exported by the atto_profiler crate
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, parse_quote, Ident, ItemFn, LitStr, Token};
+
+struct ProfileArgs {
+    name : Option<LitStr>,    // The tag of the scope, defaults to the function name
+    category : Option<LitStr> // The category of the scope
+}
+
+impl Parse for ProfileArgs {
+    // Accepts either #[profile], #[profile("name")] or #[profile(name = "name")], optionally
+    // followed by category = "category"
+    fn parse(input : ParseStream) -> syn::Result<Self> {
+        let mut args = ProfileArgs { name : None, category : None };
+        if input.peek(LitStr) {
+            args.name = Some(input.parse()?);
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+
+        while !input.is_empty() {
+            let key : Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value : LitStr = input.parse()?;
+            match key.to_string().as_str() {
+                "name" => args.name = Some(value),
+                "category" => args.category = Some(value),
+                _ => return Err(syn::Error::new(key.span(), "unknown profile argument, expected `name` or `category`"))
+            }
+
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+// Profiles every call of the function as a scope. The function body of an async fn is profiled
+// on each poll, as the future can move between threads, so there is no scope for the whole call.
+#[proc_macro_attribute]
+pub fn profile(attr : TokenStream, item : TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as ProfileArgs);
+    let mut function = parse_macro_input!(item as ItemFn);
+
+    // Leave the function untouched when profiling is compiled out
+    if !cfg!(feature = "profiling") {
+        return quote!(#function).into();
+    }
+
+    let ident = &function.sig.ident;
+    let name = args.name.unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+    let block = &function.block;
+    let new_block = if function.sig.asyncness.is_some() {
+        let category = match args.category {
+            Some(category) => quote!(Some(::atto_profiler::profile_category!(#category))),
+            None => quote!(None)
+        };
+        parse_quote!({
+            ::atto_profiler::profiler::internal::ProfileFuture::new(#category, #name, async move #block).await
+        })
+    }
+    else {
+        let category = args.category.map(|category| quote!(cat: #category,));
+        parse_quote!({
+            ::atto_profiler::profile_scope!(#category #name);
+            #block
+        })
+    };
+    *function.block = new_block;
+
+    quote!(#function).into()
+}
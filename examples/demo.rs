@@ -5,9 +5,10 @@ use std::alloc::System;
 
 use std::sync::atomic::{AtomicI32, Ordering};
 
-static mut ALLOC_COUNT : AtomicI32 = AtomicI32::new(0);
-static mut DEALLOC_COUNT : AtomicI32 = AtomicI32::new(0);
+static ALLOC_COUNT : AtomicI32 = AtomicI32::new(0);
+static DEALLOC_COUNT : AtomicI32 = AtomicI32::new(0);
 
+#[allow(dead_code)]
 struct MyAllocator;
 unsafe impl GlobalAlloc for MyAllocator {
     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
@@ -26,21 +27,22 @@ pub struct MemScope {
 }
 impl MemScope {
     pub fn new() -> MemScope {
-        unsafe {
-            MemScope {
-                alloc : ALLOC_COUNT.load(Ordering::SeqCst),
-                dealloc : DEALLOC_COUNT.load(Ordering::SeqCst),
-            }
+        MemScope {
+            alloc : ALLOC_COUNT.load(Ordering::SeqCst),
+            dealloc : DEALLOC_COUNT.load(Ordering::SeqCst),
         }
     }
 }
+impl Default for MemScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Drop for MemScope {
     fn drop(&mut self) {
-        unsafe {
-            let alloc = ALLOC_COUNT.load(Ordering::SeqCst);
-            let dealloc = DEALLOC_COUNT.load(Ordering::SeqCst);
-            println!("New Allocs {}\nWorking {}\n", alloc - self.alloc, dealloc - self.dealloc);
-        }
+        let alloc = ALLOC_COUNT.load(Ordering::SeqCst);
+        let dealloc = DEALLOC_COUNT.load(Ordering::SeqCst);
+        println!("New Allocs {}\nWorking {}\n", alloc - self.alloc, dealloc - self.dealloc);
 
     }
 }
@@ -71,9 +73,14 @@ extern crate rs_tracing;
 extern crate backtrace;
 
 use rs_tracing::*;
-mod profiler;
+use atto_profiler::*;
 use backtrace::*;
 
+#[profile]
+fn sleep_work(millis : u64) {
+    thread::sleep(time::Duration::from_millis(millis));
+}
+
 fn main() {
 
     {
@@ -83,7 +90,9 @@ fn main() {
     }
 
 
-    profile_start!(100_000);
+    profile_process_name!("AttoProfiler demo");
+    let config = profiler::internal::ProfileConfig::new(100_000).backtrace_depth(8);
+    profile_start_with_config!(config).expect("failed to start profiling");
 
     let thread1 = thread::Builder::new().name("child1".to_string()).spawn(move || {
         profile_scope!("Test scope");
@@ -127,18 +136,28 @@ fn main() {
           profile_scope!("Test scope3");
         }
         profile_begin!("Test area");
-        thread::sleep(time::Duration::from_millis(5));
+        sleep_work(5);
 
         let test_alloc = vec!(0;100);
 
-        thread::sleep(time::Duration::from_millis(5));
+        sleep_work(5);
         println!("{}", test_alloc[5]);
         profile_end!();
     }
     thread1.join().unwrap();
     thread2.join().unwrap();
 
-    let _ = profile_finish_to_file!("test2.txt");
+    for _ in 0..4 {
+        profile_frame!();
+        sleep_work(2);
+    }
+    profile_frame!();
+
+    if let Ok(stats) = profile_finish_to_file!("test2.txt") {
+        if let Some(frame_time) = stats.frame_time() {
+            println!("Frame time {:?}", frame_time);
+        }
+    }
 
     open_trace_file!("foo.txt").unwrap();
     {
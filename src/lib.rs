@@ -0,0 +1,4 @@
+
+pub mod profiler;
+
+pub use atto_profiler_macros::profile;
@@ -1,4 +1,7 @@
 
+// Starts a session where each thread holds up to tag_count records. The records of a thread are
+// allocated as it records, and the records of exited threads are kept until the session ends.
+#[cfg(feature = "profiling")]
 #[macro_export]
 macro_rules! profile_start {
     ($tag_count: expr) => {
@@ -6,6 +9,24 @@ macro_rules! profile_start {
     };
 }
 
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_start_flight_recorder {
+    ($tag_count: expr) => {
+        $crate::profiler::internal::begin_flight_recorder($tag_count)
+    };
+}
+
+// Starts a session with a ProfileConfig, profile_start! being a shorthand for ProfileConfig::new(tag_count)
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_start_with_config {
+    ($config: expr) => {
+        $crate::profiler::internal::begin_with_config($config)
+    };
+}
+
+#[cfg(feature = "profiling")]
 #[macro_export]
 macro_rules! profile_finish {
     ($writer: expr) => {
@@ -13,6 +34,7 @@ macro_rules! profile_finish {
     };
 }
 
+#[cfg(feature = "profiling")]
 #[macro_export]
 macro_rules! profile_finish_to_file {
     ($filename: expr) => {
@@ -20,32 +42,595 @@ macro_rules! profile_finish_to_file {
     };
 }
 
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_dump {
+    ($writer: expr) => {
+        $crate::profiler::internal::dump($writer)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_dump_to_file {
+    ($filename: expr) => {
+        $crate::profiler::internal::dump_to_file($filename)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_process_name {
+    ($name: expr) => {
+        $crate::profiler::internal::set_process_name($name)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_process_labels {
+    ($($label: expr),*) => {
+        $crate::profiler::internal::set_process_labels(&[$($label),*])
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_categories {
+    ($($category: expr),*) => {
+        $crate::profiler::internal::set_categories(&[$($category),*])
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_enable_category {
+    ($category: expr) => {
+        $crate::profiler::internal::enable_category($category)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_disable_category {
+    ($category: expr) => {
+        $crate::profiler::internal::disable_category($category)
+    };
+}
+
+// Declares the category of a call site, used by the macros taking a `cat: "name"` first argument
+#[cfg(feature = "profiling")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! profile_category {
+    ($category: expr) => {{
+        static CATEGORY : $crate::profiler::internal::Category = $crate::profiler::internal::Category::new($category);
+        &CATEGORY
+    }};
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_begin {
+    (cat: $category: expr, $($rest: tt)+) => {
+        $crate::profile_begin!(@ Some($crate::profile_category!($category)), $($rest)+)
+    };
+    (@ $category: expr, $tag: expr) => {
+        $crate::profiler::internal::profile_begin($category, $tag)
+    };
+    (@ $category: expr, $tag: expr, $($key: ident = $value: expr),+ $(,)?) => {
+        $crate::profiler::internal::profile_begin_with_args($category, $tag, &[$((stringify!($key), $crate::profiler::internal::IntoArg::to_arg(&$value))),+])
+    };
+    (@ $category: expr, $format: literal, $($arg: tt)+) => {
+        $crate::profiler::internal::profile_begin_fmt($category, format_args!($format, $($arg)+))
+    };
+    ($($rest: tt)+) => {
+        $crate::profile_begin!(@ None, $($rest)+)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_begin_dynamic {
+    (cat: $category: expr, $name: expr) => {
+        $crate::profiler::internal::profile_begin_dynamic(Some($crate::profile_category!($category)), AsRef::<str>::as_ref(&$name))
+    };
+    ($name: expr) => {
+        $crate::profiler::internal::profile_begin_dynamic(None, AsRef::<str>::as_ref(&$name))
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_instant {
+    (cat: $category: expr, $($rest: tt)+) => {
+        $crate::profile_instant!(@ Some($crate::profile_category!($category)), $($rest)+)
+    };
+    (@ $category: expr, $tag: expr) => {
+        $crate::profile_instant!(@ $category, $tag, thread)
+    };
+    (@ $category: expr, $tag: expr, thread) => {
+        $crate::profiler::internal::profile_instant($category, $tag, $crate::profiler::internal::InstantScope::Thread)
+    };
+    (@ $category: expr, $tag: expr, process) => {
+        $crate::profiler::internal::profile_instant($category, $tag, $crate::profiler::internal::InstantScope::Process)
+    };
+    (@ $category: expr, $tag: expr, global) => {
+        $crate::profiler::internal::profile_instant($category, $tag, $crate::profiler::internal::InstantScope::Global)
+    };
+    ($($rest: tt)+) => {
+        $crate::profile_instant!(@ None, $($rest)+)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_counter {
+    (cat: $category: expr, $($rest: tt)+) => {
+        $crate::profile_counter!(@ Some($crate::profile_category!($category)), $($rest)+)
+    };
+    (@ $category: expr, $tag: expr, $($series: ident = $value: expr),+ $(,)?) => {
+        $crate::profiler::internal::profile_counter_series($category, $tag, &[$((stringify!($series), $value as f64)),+])
+    };
+    (@ $category: expr, $tag: expr, $value: expr) => {
+        $crate::profiler::internal::profile_counter($category, $tag, $value as f64)
+    };
+    ($($rest: tt)+) => {
+        $crate::profile_counter!(@ None, $($rest)+)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_async_begin {
+    (cat: $category: expr, $tag: expr, $id: expr) => {
+        $crate::profiler::internal::profile_async_begin(Some($crate::profile_category!($category)), $tag, $id as u64)
+    };
+    ($tag: expr, $id: expr) => {
+        $crate::profiler::internal::profile_async_begin(None, $tag, $id as u64)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_async_end {
+    (cat: $category: expr, $tag: expr, $id: expr) => {
+        $crate::profiler::internal::profile_async_end(Some($crate::profile_category!($category)), $tag, $id as u64)
+    };
+    ($tag: expr, $id: expr) => {
+        $crate::profiler::internal::profile_async_end(None, $tag, $id as u64)
+    };
+}
+
+// Links the enclosing scope to the scopes of the later phases of the flow, which can be on other threads.
+// Flows are matched by category and id, so every phase of a flow must be given the same category or none.
+// Give a flow the category of its enclosing scope, as a flow in a scope that is filtered out binds to nothing.
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_flow_start {
+    (cat: $category: expr, $id: expr) => {
+        $crate::profiler::internal::profile_flow(Some($crate::profile_category!($category)), $crate::profiler::internal::FlowPhase::Start, $id as u64)
+    };
+    ($id: expr) => {
+        $crate::profiler::internal::profile_flow(None, $crate::profiler::internal::FlowPhase::Start, $id as u64)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_flow_step {
+    (cat: $category: expr, $id: expr) => {
+        $crate::profiler::internal::profile_flow(Some($crate::profile_category!($category)), $crate::profiler::internal::FlowPhase::Step, $id as u64)
+    };
+    ($id: expr) => {
+        $crate::profiler::internal::profile_flow(None, $crate::profiler::internal::FlowPhase::Step, $id as u64)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_flow_end {
+    (cat: $category: expr, $id: expr) => {
+        $crate::profiler::internal::profile_flow(Some($crate::profile_category!($category)), $crate::profiler::internal::FlowPhase::End, $id as u64)
+    };
+    ($id: expr) => {
+        $crate::profiler::internal::profile_flow(None, $crate::profiler::internal::FlowPhase::End, $id as u64)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_frame {
+    () => {
+        $crate::profiler::internal::profile_frame()
+    };
+}
+
+// The end of a profile_begin! with a category must be given the same category
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_end {
+    () => {
+        $crate::profiler::internal::profile_end(None)
+    };
+    (cat: $category: expr) => {
+        $crate::profiler::internal::profile_end(Some($crate::profile_category!($category)))
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_scope {
+    (cat: $category: expr, $($rest: tt)+) => {
+        $crate::profile_scope!(@ Some($crate::profile_category!($category)), $($rest)+)
+    };
+    (@ $category: expr, $tag: expr) => {
+        let _profile_guard = $crate::profiler::internal::ProfileScope::new($category, $tag);
+    };
+    (@ $category: expr, $tag: expr, $($key: ident = $value: expr),+ $(,)?) => {
+        let _profile_guard = $crate::profiler::internal::ProfileScope::new_with_args($category, $tag,
+            &[$((stringify!($key), $crate::profiler::internal::IntoArg::to_arg(&$value))),+]);
+    };
+    (@ $category: expr, $format: literal, $($arg: tt)+) => {
+        let _profile_guard = $crate::profiler::internal::ProfileScope::new_fmt($category, format_args!($format, $($arg)+));
+    };
+    ($($rest: tt)+) => {
+        $crate::profile_scope!(@ None, $($rest)+)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_scope_dynamic {
+    (cat: $category: expr, $name: expr) => {
+        let _profile_guard = $crate::profiler::internal::ProfileScope::new_dynamic(Some($crate::profile_category!($category)), AsRef::<str>::as_ref(&$name));
+    };
+    ($name: expr) => {
+        let _profile_guard = $crate::profiler::internal::ProfileScope::new_dynamic(None, AsRef::<str>::as_ref(&$name));
+    };
+}
+
+// Profiles the enclosing function, tagged with its path optionally trimmed to the last segments.
+// The type name giving the path can't be trimmed at compile time, so it is trimmed on the first call
+// of each function and cached, with later calls paying a single OnceLock load.
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! profile_function {
+    () => {
+        $crate::profile_function!(@ None, 0)
+    };
+    (cat: $category: expr) => {
+        $crate::profile_function!(@ Some($crate::profile_category!($category)), 0)
+    };
+    (cat: $category: expr, $segments: expr) => {
+        $crate::profile_function!(@ Some($crate::profile_category!($category)), $segments)
+    };
+    (@ $category: expr, $segments: expr) => {
+        let _profile_guard = $crate::profiler::internal::ProfileScope::new($category, {
+            fn f() {}
+            // Only determine the name on the first call
+            static NAME : std::sync::OnceLock<&'static str> = std::sync::OnceLock::new();
+            *NAME.get_or_init(|| $crate::profiler::internal::function_name($crate::profiler::internal::type_name_of(f), $segments))
+        });
+    };
+    ($segments: expr) => {
+        $crate::profile_function!(@ None, $segments)
+    };
+}
+
+#[cfg(feature = "profiling")]
+#[macro_export]
+macro_rules! use_profile_memory_allocator {
+    () => {
+        #[global_allocator]
+        static A: $crate::profiler::internal::MemTrackAllocator = $crate::profiler::internal::MemTrackAllocator;
+    };
+}
+
+// With the profiling feature disabled the macros compile to nothing, only type checking their arguments
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_start {
+    ($tag_count: expr) => {{
+        if false { let _ : usize = $tag_count; }
+        Result::<(), $crate::profiler::internal::ProfilerError>::Ok(())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_start_flight_recorder {
+    ($tag_count: expr) => {{
+        if false { let _ : usize = $tag_count; }
+        Result::<(), $crate::profiler::internal::ProfilerError>::Ok(())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_start_with_config {
+    ($config: expr) => {{
+        if false { let _ : $crate::profiler::internal::ProfileConfig = $config; }
+        Result::<(), $crate::profiler::internal::ProfilerError>::Ok(())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_finish {
+    ($writer: expr) => {{
+        if false { let _ : &mut dyn std::io::Write = $writer; }
+        Result::<_, $crate::profiler::internal::ProfilerError>::Ok($crate::profiler::internal::ProfileStats::default())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_finish_to_file {
+    ($filename: expr) => {{
+        if false { let _ : &str = $filename; }
+        Result::<_, $crate::profiler::internal::ProfilerError>::Ok($crate::profiler::internal::ProfileStats::default())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_dump {
+    ($writer: expr) => {{
+        if false { let _ : &mut dyn std::io::Write = $writer; }
+        Result::<_, $crate::profiler::internal::ProfilerError>::Ok($crate::profiler::internal::ProfileStats::default())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_dump_to_file {
+    ($filename: expr) => {{
+        if false { let _ : &str = $filename; }
+        Result::<_, $crate::profiler::internal::ProfilerError>::Ok($crate::profiler::internal::ProfileStats::default())
+    }};
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_process_name {
+    ($name: expr) => {
+        if false { let _ : &str = $name; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_process_labels {
+    ($($label: expr),*) => {
+        if false { let _ : &[&str] = &[$($label),*]; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_categories {
+    ($($category: expr),*) => {
+        if false { let _ : &[&str] = &[$($category),*]; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_enable_category {
+    ($category: expr) => {
+        if false { let _ : &str = $category; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_disable_category {
+    ($category: expr) => {
+        if false { let _ : &str = $category; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
 #[macro_export]
 macro_rules! profile_begin {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_begin!($($rest)+)
+    }};
+    ($tag: expr) => {
+        if false { let _ : &'static str = $tag; }
+    };
+    ($tag: expr, $($key: ident = $value: expr),+ $(,)?) => {
+        if false {
+            let _ : &'static str = $tag;
+            $(let _ = $crate::profiler::internal::IntoArg::to_arg(&$value);)+
+        }
+    };
+    ($format: literal, $($arg: tt)+) => {
+        if false { let _ = format_args!($format, $($arg)+); }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_begin_dynamic {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_begin_dynamic!($($rest)+)
+    }};
+    ($name: expr) => {
+        if false { let _ : &str = AsRef::<str>::as_ref(&$name); }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_instant {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_instant!($($rest)+)
+    }};
     ($tag: expr) => {
-        $crate::profiler::internal::profile_begin($tag)
+        if false { let _ : &'static str = $tag; }
+    };
+    ($tag: expr, thread) => {
+        $crate::profile_instant!($tag)
+    };
+    ($tag: expr, process) => {
+        $crate::profile_instant!($tag)
+    };
+    ($tag: expr, global) => {
+        $crate::profile_instant!($tag)
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_counter {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_counter!($($rest)+)
+    }};
+    ($tag: expr, $($series: ident = $value: expr),+ $(,)?) => {
+        if false { let _ : &'static str = $tag; $(let _ = $value as f64;)+ }
+    };
+    ($tag: expr, $value: expr) => {
+        if false { let _ : &'static str = $tag; let _ = $value as f64; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_async_begin {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_async_begin!($($rest)+)
+    }};
+    ($tag: expr, $id: expr) => {
+        if false { let _ : &'static str = $tag; let _ = $id as u64; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_async_end {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_async_end!($($rest)+)
+    }};
+    ($tag: expr, $id: expr) => {
+        if false { let _ : &'static str = $tag; let _ = $id as u64; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_flow_start {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_flow_start!($($rest)+)
+    }};
+    ($id: expr) => {
+        if false { let _ = $id as u64; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_flow_step {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_flow_step!($($rest)+)
+    }};
+    ($id: expr) => {
+        if false { let _ = $id as u64; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_flow_end {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_flow_end!($($rest)+)
+    }};
+    ($id: expr) => {
+        if false { let _ = $id as u64; }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_frame {
+    () => {
+        ()
     };
 }
 
+#[cfg(not(feature = "profiling"))]
 #[macro_export]
 macro_rules! profile_end {
     () => {
-        $crate::profiler::internal::profile_end()
+        ()
+    };
+    (cat: $category: expr) => {
+        if false { let _ : &'static str = $category; }
     };
 }
 
+#[cfg(not(feature = "profiling"))]
 #[macro_export]
 macro_rules! profile_scope {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_scope!($($rest)+)
+    }};
     ($tag: expr) => {
-        let _profile_guard = $crate::profiler::internal::ProfileScope::new($tag);
+        if false { let _ : &'static str = $tag; }
+    };
+    ($tag: expr, $($key: ident = $value: expr),+ $(,)?) => {
+        if false {
+            let _ : &'static str = $tag;
+            $(let _ = $crate::profiler::internal::IntoArg::to_arg(&$value);)+
+        }
+    };
+    ($format: literal, $($arg: tt)+) => {
+        if false { let _ = format_args!($format, $($arg)+); }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_scope_dynamic {
+    (cat: $category: expr, $($rest: tt)+) => {{
+        if false { let _ : &'static str = $category; }
+        $crate::profile_scope_dynamic!($($rest)+)
+    }};
+    ($name: expr) => {
+        if false { let _ : &str = AsRef::<str>::as_ref(&$name); }
+    };
+}
+
+#[cfg(not(feature = "profiling"))]
+#[macro_export]
+macro_rules! profile_function {
+    () => {};
+    (cat: $category: expr $(, $segments: expr)?) => {
+        if false { let _ : &'static str = $category; $(let _ : usize = $segments;)? }
+    };
+    ($segments: expr) => {
+        if false { let _ : usize = $segments; }
     };
 }
 
+#[cfg(not(feature = "profiling"))]
 #[macro_export]
 macro_rules! use_profile_memory_allocator {
     () => {
         #[global_allocator]
-        static A: $crate::profiler::internal::MemTrackAllocator = $crate::profiler::internal::MemTrackAllocator;
+        static A: std::alloc::System = std::alloc::System;
     };
 }
 
@@ -64,8 +649,8 @@ mod sys {
         TRUE, BOOL, DWORD, HMODULE, LPDWORD, PDWORD, PUCHAR, PULONG, UCHAR, ULONG, USHORT, WORD,
     };
 
-    #[derive(PartialEq, Clone, Copy)]
-    pub struct TimePoint(i64);
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+    pub struct TimePoint(pub i64); // In ticks of the clock
     pub struct StopWatch {
         frequency : i64
     }
@@ -92,8 +677,16 @@ mod sys {
             }
         }
 
-        pub fn get_milliseconds(&self, a : &TimePoint, b : &TimePoint) -> i64 {
-            mul_div_i64(b.0 - a.0, 1000_000, self.frequency)
+        pub fn with_frequency(frequency : i64) -> StopWatch {
+            StopWatch { frequency }
+        }
+
+        pub fn frequency(&self) -> i64 {
+            self.frequency
+        }
+
+        pub fn get_nanoseconds(&self, a : &TimePoint, b : &TimePoint) -> i64 {
+            mul_div_i64(b.0 - a.0, 1_000_000_000, self.frequency)
         }
     }
 
@@ -235,105 +828,1283 @@ mod sys {
 */
 }
 
-pub mod internal {
-    use super::sys;
-    use backtrace::*;
+#[cfg(unix)]
+mod sys {
+    use std::mem;
+    use std::cell::UnsafeCell;
+    use std::ops::{Deref, DerefMut};
 
-    use std::io;
-    use std::io::{Write, BufWriter};
-    use std::alloc::{System, GlobalAlloc, Layout};
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+    pub struct TimePoint(pub i64); // In ticks of the clock
+    pub struct StopWatch {
+        frequency : i64
+    }
+
+    impl StopWatch {
+        pub fn new() -> StopWatch {
+            // clock_gettime reports in nanoseconds
+            StopWatch {
+                frequency : 1_000_000_000
+            }
+        }
+
+        pub fn get_time() -> TimePoint {
+            unsafe {
+                let mut t : libc::timespec = mem::zeroed();
+                libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut t);
+                TimePoint(t.tv_sec as i64 * 1_000_000_000 + t.tv_nsec as i64)
+            }
+        }
+
+        pub fn with_frequency(frequency : i64) -> StopWatch {
+            StopWatch { frequency }
+        }
+
+        pub fn frequency(&self) -> i64 {
+            self.frequency
+        }
+
+        pub fn get_nanoseconds(&self, a : &TimePoint, b : &TimePoint) -> i64 {
+            mul_div_i64(b.0 - a.0, 1_000_000_000, self.frequency)
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn get_thread_id() -> u32 {
+        unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn get_thread_id() -> u32 {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        // No portable gettid - hand out a process unique id on first use
+        static NEXT_ID : AtomicU32 = AtomicU32::new(1);
+        thread_local!(static THREAD_ID : u32 = NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        THREAD_ID.with(|id| *id)
+    }
+
+    pub struct ReentrantMutex<T: ?Sized> {
+         inner : Box<UnsafeCell<libc::pthread_mutex_t>>,
+         lock_count : u32,
+         data: UnsafeCell<T>
+    }
+    pub struct MutexGuard<'a, T: ?Sized + 'a> {
+        // funny underscores due to how Deref/DerefMut currently work (they
+        // disregard field privacy).
+        __lock: &'a mut ReentrantMutex<T>
+    }
+
+    impl<T> ReentrantMutex<T> {
+        pub fn new(t: T) -> ReentrantMutex<T> {
+            unsafe {
+                let ret = ReentrantMutex {
+                     inner: Box::new(UnsafeCell::new(mem::zeroed())),
+                     lock_count : 0,
+                     data: UnsafeCell::new(t)
+                };
+
+                // The mutex is boxed so it never moves after initialization
+                let mut attr : libc::pthread_mutexattr_t = mem::zeroed();
+                libc::pthread_mutexattr_init(&mut attr);
+                libc::pthread_mutexattr_settype(&mut attr, libc::PTHREAD_MUTEX_RECURSIVE);
+                libc::pthread_mutex_init(ret.inner.get(), &attr);
+                libc::pthread_mutexattr_destroy(&mut attr);
+                ret
+            }
+        }
+    }
+
+    impl<T: ?Sized> Drop for ReentrantMutex<T> {
+        fn drop(&mut self) {
+            unsafe {
+                libc::pthread_mutex_destroy(self.inner.get());
+            }
+        }
+    }
+
+    impl<'mutex, T: ?Sized> MutexGuard<'mutex, T> {
+        pub fn new(lock: &'mutex mut ReentrantMutex<T>) -> Result<MutexGuard<'mutex, T>,()> {
+            unsafe {
+                if libc::pthread_mutex_lock(lock.inner.get()) != 0 {
+                    return Err(());
+                }
+                lock.lock_count += 1;
+                Ok(MutexGuard { __lock: lock })
+            }
+        }
+    }
+
+    impl<'mutex, T: ?Sized> MutexGuard<'mutex, T> {
+        pub fn new_no_recurse(lock: &'mutex mut ReentrantMutex<T>) -> Result<MutexGuard<'mutex, T>,()> {
+            unsafe {
+                if libc::pthread_mutex_lock(lock.inner.get()) != 0 {
+                    return Err(());
+                }
+                if lock.lock_count > 0 {
+                    libc::pthread_mutex_unlock(lock.inner.get());
+                    return Err(());
+                }
+
+                lock.lock_count += 1;
+                Ok(MutexGuard { __lock: lock })
+            }
+        }
+    }
 
-    use std::sync::Once;
-    use std::sync::atomic::{AtomicBool, Ordering};
+    impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+        #[inline]
+        fn drop(&mut self) {
+            unsafe {
+                self.__lock.lock_count -= 1;
+                libc::pthread_mutex_unlock(self.__lock.inner.get());
+            }
+        }
+    }
+
+    impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { &*self.__lock.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.__lock.data.get() }
+        }
+    }
+
+    // Computes (value*numer)/denom without overflow, as long as both
+    // (numer*denom) and the overall result fit into i64 (which is the case
+    // for our time conversions).
+    pub fn mul_div_i64(value: i64, numer: i64, denom: i64) -> i64 {
+        let q = value / denom;
+        let r = value % denom;
+        // Decompose value as (value/denom*denom + value%denom),
+        // substitute into (value*numer)/denom and simplify.
+        // r < denom, so (denom*numer) is the upper bound of (r*numer)
+        q * numer + r * numer / denom
+    }
+}
+
+pub mod internal {
+    use super::sys;
+
+    use std::io;
+    use std::io::{Write, BufWriter};
+    use std::alloc::{System, GlobalAlloc, Layout};
+
+    use std::sync::{Arc, Once, OnceLock};
+    use std::sync::atomic::{AtomicBool, AtomicI64, AtomicPtr, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+    use std::cell::{Cell, RefCell, UnsafeCell};
     use std::collections::HashMap;
-    use std::ops::DerefMut;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::fmt;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    // A value attached to a profile event
+    pub enum Arg<'a> {
+        Int(i64),
+        UInt(u64),
+        Float(f64),
+        Bool(bool),
+        Str(&'a str)
+    }
+
+    pub trait IntoArg {
+        fn to_arg(&self) -> Arg<'_>;
+    }
+
+    macro_rules! impl_into_arg {
+        ($variant: ident, $cast: ty, $($t: ty),*) => {
+            $(impl IntoArg for $t {
+                fn to_arg(&self) -> Arg<'_> {
+                    Arg::$variant(*self as $cast)
+                }
+            })*
+        };
+    }
+    impl_into_arg!(Int, i64, i8, i16, i32, i64, isize);
+    impl_into_arg!(UInt, u64, u8, u16, u32, u64, usize);
+    impl_into_arg!(Float, f64, f32, f64);
+
+    impl IntoArg for bool {
+        fn to_arg(&self) -> Arg<'_> {
+            Arg::Bool(*self)
+        }
+    }
+
+    impl IntoArg for str {
+        fn to_arg(&self) -> Arg<'_> {
+            Arg::Str(self)
+        }
+    }
+
+    impl IntoArg for String {
+        fn to_arg(&self) -> Arg<'_> {
+            Arg::Str(self)
+        }
+    }
+
+    impl<T : IntoArg + ?Sized> IntoArg for &T {
+        fn to_arg(&self) -> Arg<'_> {
+            (**self).to_arg()
+        }
+    }
+
+    // The name of a tag, either static or interned in the record buffer of the thread
+    #[derive(Clone, Copy)]
+    enum Tag
+    {
+        Static(&'static str),
+        Dynamic(u32)
+    }
+
+    // The scope an instant event is drawn over in the viewer
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum InstantScope {
+        Thread,
+        Process,
+        Global
+    }
+
+    // An argument value as stored in the record buffer of the thread
+    #[derive(Clone)]
+    enum ArgValue
+    {
+        Int(i64),
+        UInt(u64),
+        Float(f64),
+        Bool(bool),
+        Str(Tag)
+    }
 
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum FlowPhase {
+        Start,
+        Step,
+        End
+    }
+
+    #[derive(Clone)]
+    enum CounterValues
+    {
+        Single(f64),
+        Series(Box<[(&'static str, f64)]>) // Only allocated for multiple series
+    }
+
+    // The return addresses of an allocation, only captured with a backtrace depth set
+    type Stack = Option<Box<[usize]>>;
+
+    // The key/value arguments of an event, only allocated when an event has arguments
+    type Args = Option<Box<[(&'static str, ArgValue)]>>;
+
+    #[derive(Clone)]
     enum TagType
     {
-        Begin(&'static str),
+        Begin(Tag, Args),
         End,
-        Complete(&'static str, i64), // A complete event holds a duration of the event
-        Allocate(usize),
-        Deallocate(usize)
+        Complete(Tag, Args, sys::TimePoint), // A complete event holds the end time of the event
+        Allocate(usize, Stack),
+        Deallocate(usize),
+        Instant(Tag, InstantScope),
+        Counter(Tag, CounterValues),
+        AsyncBegin(Tag, u64), // Async events are matched by name and id, not by thread
+        AsyncEnd(Tag, u64),
+        Flow(FlowPhase, u64), // Flow events bind to the scope open on the thread at the time of the event
+        Frame(u64)            // A frame boundary, holding the index of the frame that starts
     }
  
+    #[derive(Clone)]
     struct ProfileRecord {
         time : sys::TimePoint,        // The time of the profile data
-        thread_id : u32,  // The id of the thread
         tag : TagType,         // The tag used in profiling - if empty is an end event
+        category : Option<&'static Category>, // The category written as "cat"
     }
 
-    struct Tags {
+    struct Tags<'a> {
         index : usize,           // The index of the thread
-        tags : Vec<&'static str> // The tag stack
+        tags : Vec<(&'a str, sys::TimePoint)> // The tag stack, with the begin time of each tag
+    }
+
+    // The number of records dropped due to a full buffer
+    #[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+    pub struct DroppedRecords {
+        pub begin : usize,    // Begin events (profile_begin!)
+        pub end : usize,      // End events (profile_end!)
+        pub complete : usize, // Scope events (profile_scope!)
+        pub alloc : usize,    // Allocation and deallocation events
+        pub instant : usize,  // Instant events (profile_instant!)
+        pub counter : usize,  // Counter events (profile_counter!)
+        pub async_span : usize, // Async begin and end events (profile_async_begin!)
+        pub flow : usize,     // Flow events (profile_flow_start!)
+        pub frame : usize,    // Frame boundaries (profile_frame!)
+    }
+
+    impl DroppedRecords {
+        pub fn total(&self) -> usize {
+            self.counts().iter().map(|(_, count)| count).sum()
+        }
+
+        fn add(&mut self, other : &DroppedRecords) {
+            self.begin += other.begin;
+            self.end += other.end;
+            self.complete += other.complete;
+            self.alloc += other.alloc;
+            self.instant += other.instant;
+            self.counter += other.counter;
+            self.async_span += other.async_span;
+            self.flow += other.flow;
+            self.frame += other.frame;
+        }
+
+        fn counts(&self) -> [(&'static str, usize); 9] {
+            [("begin", self.begin),
+             ("end", self.end),
+             ("complete", self.complete),
+             ("alloc", self.alloc),
+             ("instant", self.instant),
+             ("counter", self.counter),
+             ("async_span", self.async_span),
+             ("flow", self.flow),
+             ("frame", self.frame)]
+        }
+
+        // Writes the counts as json members, with each name prefixed
+        fn write_json(&self, w : &mut dyn Write, prefix : &str) -> io::Result<()> {
+            for (i, (name, count)) in self.counts().iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                write!(w, "\"{}{}\":{}", prefix, name, count)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct ThreadStats {
+        pub thread_id : u32,            // The id of the thread
+        pub name : Option<String>,      // The name of the thread
+        pub recorded : usize,           // The number of records written out
+        pub overwritten : usize,        // The number of records overwritten in flight recorder mode
+        pub dropped : DroppedRecords,   // The records dropped as the buffer was full
+    }
+
+    // The timing of a frame, from one profile_frame! boundary to the next
+    #[derive(Clone, Debug)]
+    pub struct FrameStats {
+        pub frame : u64,                    // The index of the frame in the session
+        pub start : Duration,               // The start of the frame from the session start
+        pub duration : Duration,            // The duration of the frame
+        pub tags : Vec<(String, Duration)>, // The time spent in each tag within the frame, longest first
+    }
+
+    // The frame time distribution of a session
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct FrameTimeStats {
+        pub count : usize,
+        pub min : Duration,
+        pub avg : Duration,
+        pub max : Duration,
+        pub p99 : Duration,
+    }
+
+    // The errors of starting and finishing a profile session
+    #[derive(Debug)]
+    pub enum ProfilerError {
+        NotStarted,      // No session is active
+        AlreadyRunning,  // A session is already active
+        LockBusy,        // The profiler can't be locked, as when finishing while formatting a tag name
+        Overflow,        // The record count is too large to allocate
+        Io(io::Error)    // Writing the profile failed
+    }
+
+    impl fmt::Display for ProfilerError {
+        fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                ProfilerError::NotStarted => write!(f, "no profile session is active"),
+                ProfilerError::AlreadyRunning => write!(f, "a profile session is already active"),
+                ProfilerError::LockBusy => write!(f, "the profiler lock is busy"),
+                ProfilerError::Overflow => write!(f, "the profile record count is too large"),
+                ProfilerError::Io(e) => write!(f, "failed to write the profile: {}", e)
+            }
+        }
+    }
+
+    impl std::error::Error for ProfilerError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                ProfilerError::Io(e) => Some(e),
+                _ => None
+            }
+        }
+    }
+
+    impl From<io::Error> for ProfilerError {
+        fn from(e : io::Error) -> Self {
+            ProfilerError::Io(e)
+        }
+    }
+
+    // The statistics of a written profile session
+    #[derive(Clone, Debug, Default)]
+    pub struct ProfileStats {
+        pub threads : Vec<ThreadStats>,
+        pub frames : Vec<FrameStats>    // The frames between the recorded frame boundaries
+    }
+
+    impl ProfileStats {
+        // The records dropped over all threads
+        pub fn dropped(&self) -> DroppedRecords {
+            let mut ret = DroppedRecords::default();
+            for thread in self.threads.iter() {
+                ret.add(&thread.dropped);
+            }
+            ret
+        }
+
+        // The min/avg/max/p99 frame time, None if no complete frame was recorded
+        pub fn frame_time(&self) -> Option<FrameTimeStats> {
+            if self.frames.is_empty() {
+                return None;
+            }
+
+            let mut durations : Vec<Duration> = self.frames.iter().map(|frame| frame.duration).collect();
+            durations.sort_unstable();
+            let count = durations.len();
+            // Nearest rank percentile
+            let p99_index = (count * 99).div_ceil(100) - 1;
+            Some(FrameTimeStats {
+                count,
+                min : durations[0],
+                avg : durations.iter().sum::<Duration>() / count as u32,
+                max : durations[count - 1],
+                p99 : durations[p99_index]
+            })
+        }
+    }
+
+    // The number of records a thread first allocates
+    const INITIAL_RECORDS : usize = 64;
+
+    // A tag name built at runtime, kept while a held record uses it
+    #[derive(Clone)]
+    struct InternedName {
+        name : Arc<str>,
+        uses : usize            // The number of held records using the name
+    }
+
+    struct RecordBuffer {
+        records : Vec<ProfileRecord>,           // The profiling records, grown up to the capacity as needed
+        capacity : usize,                       // The maximum number of records held
+        written : usize,                        // The number of records ever written to the buffer
+        wrap : bool,                            // If the oldest records are overwritten when full
+        backtrace_depth : usize,                // The number of frames captured for each allocation
+        dropped : DroppedRecords,               // The number of records dropped as the buffer was full
+        overflow_time : Option<sys::TimePoint>, // The time the first record was dropped
+        names : Vec<Option<InternedName>>,      // The interned dynamic tag names, None once unused
+        free_names : Vec<u32>,                  // The ids of the unused names, reused first
+        name_ids : HashMap<Arc<str>, u32>,      // The index of each interned tag name
+        scratch : String,                       // Buffer to format dynamic tag names into
+    }
+
+    impl RecordBuffer {
+        // Interns a tag name built at runtime for a record. The name is freed once no held record
+        // uses it, so only the names of the records held by the buffer are kept.
+        fn intern(&mut self, name : &str) -> Tag {
+            if let Some(&id) = self.name_ids.get(name) {
+                if let Some(ref mut interned) = self.names[id as usize] {
+                    interned.uses += 1;
+                }
+                return Tag::Dynamic(id);
+            }
+
+            let name : Arc<str> = Arc::from(name);
+            let interned = Some(InternedName { name : name.clone(), uses : 1 });
+            let id = match self.free_names.pop() {
+                Some(id) => {
+                    self.names[id as usize] = interned;
+                    id
+                }
+                None => {
+                    self.names.push(interned);
+                    (self.names.len() - 1) as u32
+                }
+            };
+            self.name_ids.insert(name, id);
+            Tag::Dynamic(id)
+        }
+
+        fn release(&mut self, tag : &Tag) {
+            if let Tag::Dynamic(id) = *tag {
+                let entry = &mut self.names[id as usize];
+                if let Some(ref mut interned) = entry {
+                    interned.uses -= 1;
+                    if interned.uses == 0 {
+                        self.name_ids.remove(&interned.name);
+                        *entry = None;
+                        self.free_names.push(id);
+                    }
+                }
+            }
+        }
+
+        // Releases the names used by a record that is dropped or overwritten
+        fn release_record(&mut self, record : &ProfileRecord) {
+            let (tag, args) = match record.tag {
+                TagType::Begin(ref tag, ref args) | TagType::Complete(ref tag, ref args, _) => (tag, args),
+                TagType::Instant(ref tag, _) | TagType::Counter(ref tag, _) |
+                TagType::AsyncBegin(ref tag, _) | TagType::AsyncEnd(ref tag, _) => (tag, &None),
+                _ => return
+            };
+            self.release(tag);
+            for (_, value) in args.iter().flatten() {
+                if let ArgValue::Str(ref tag) = *value {
+                    self.release(tag);
+                }
+            }
+        }
+
+        // Frees the records and names of a written session
+        fn free(&mut self) {
+            self.records = vec![];
+            self.names = vec![];
+            self.free_names = vec![];
+            self.name_ids = HashMap::new();
+            self.scratch = String::new();
+        }
+
+        // Copies the records and their names, for writing while the thread keeps recording
+        fn snapshot(&self) -> RecordBuffer {
+            RecordBuffer {
+                records : self.records.clone(),
+                capacity : self.capacity,
+                written : self.written,
+                wrap : self.wrap,
+                backtrace_depth : self.backtrace_depth,
+                dropped : self.dropped,
+                overflow_time : self.overflow_time,
+                names : self.names.clone(),
+                free_names : vec![],
+                name_ids : HashMap::new(),
+                scratch : String::new()
+            }
+        }
+
+        fn intern_fmt(&mut self, args : fmt::Arguments) -> Tag {
+            // Format strings without arguments are static
+            if let Some(name) = args.as_str() {
+                return Tag::Static(name);
+            }
+
+            let mut scratch = std::mem::take(&mut self.scratch);
+            scratch.clear();
+            let _ = fmt::Write::write_fmt(&mut scratch, args);
+            let tag = self.intern(&scratch);
+            self.scratch = scratch;
+            tag
+        }
+
+        fn add_args(&mut self, args : &[(&'static str, Arg)]) -> Args {
+            if args.is_empty() {
+                return None;
+            }
+
+            Some(args.iter().map(|(key, arg)| {
+                let value = match *arg {
+                    Arg::Int(v) => ArgValue::Int(v),
+                    Arg::UInt(v) => ArgValue::UInt(v),
+                    Arg::Float(v) => ArgValue::Float(v),
+                    Arg::Bool(v) => ArgValue::Bool(v),
+                    Arg::Str(v) => ArgValue::Str(self.intern(v))
+                };
+                (*key, value)
+            }).collect())
+        }
+
+        // Appends the arguments as a json "args" object
+        fn write_args(&self, args : &Args, out : &mut String) {
+            use std::fmt::Write;
+
+            let args = match args {
+                Some(args) => args,
+                None => return
+            };
+
+            let mut clean_buffer = String::new();
+            out.push_str(",\"args\":{");
+            for (i, (key, value)) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "\"{}\":", clean_json_str(key, &mut clean_buffer));
+                let _ = match *value {
+                    ArgValue::Int(v) => write!(out, "{}", v),
+                    ArgValue::UInt(v) => write!(out, "{}", v),
+                    ArgValue::Float(v) => write_json_f64(out, v),
+                    ArgValue::Bool(v) => write!(out, "{}", v),
+                    ArgValue::Str(ref t) => write!(out, "\"{}\"", clean_json_str(self.tag_name(t), &mut clean_buffer))
+                };
+            }
+            out.push('}');
+        }
+
+        fn tag_name(&self, tag : &Tag) -> &str {
+            match *tag {
+                Tag::Static(name) => name,
+                Tag::Dynamic(id) => self.names[id as usize].as_ref().map_or("", |n| &n.name)
+            }
+        }
+
+        // Returns the sequence number of the added record, None if the buffer is full
+        fn add_record(&mut self, record : ProfileRecord) -> Option<usize> {
+            match self.push(record) {
+                Ok(index) => Some(index),
+                Err(record) => {
+                    self.release_record(&record);
+                    match record.tag {
+                        TagType::Begin(..) => self.dropped.begin += 1,
+                        TagType::End => self.dropped.end += 1,
+                        TagType::Complete(..) => self.dropped.complete += 1,
+                        TagType::Allocate(..) | TagType::Deallocate(_) => self.dropped.alloc += 1,
+                        TagType::Instant(..) => self.dropped.instant += 1,
+                        TagType::Counter(..) => self.dropped.counter += 1,
+                        TagType::AsyncBegin(..) | TagType::AsyncEnd(..) => self.dropped.async_span += 1,
+                        TagType::Flow(..) => self.dropped.flow += 1,
+                        TagType::Frame(_) => self.dropped.frame += 1,
+                    }
+                    self.overflow_time.get_or_insert(record.time);
+                    None
+                }
+            }
+        }
+
+        // Adds the begin record of a scope, counted as a complete event if dropped
+        fn add_scope_record(&mut self, record : ProfileRecord) -> Option<usize> {
+            match self.push(record) {
+                Ok(index) => Some(index),
+                Err(record) => {
+                    self.release_record(&record);
+                    self.dropped.complete += 1;
+                    self.overflow_time.get_or_insert(record.time);
+                    None
+                }
+            }
+        }
+
+        fn push(&mut self, record : ProfileRecord) -> Result<usize, ProfileRecord> {
+            let len = self.records.len();
+            if len < self.capacity {
+                // Double the records as they fill, so threads recording little hold little memory
+                if len == self.records.capacity() &&
+                    self.records.try_reserve_exact(len.max(INITIAL_RECORDS).min(self.capacity - len)).is_err() {
+                    // Out of memory, so keep to the records held
+                    self.capacity = len;
+                }
+            }
+            if len < self.capacity {
+                self.records.push(record);
+            }
+            else if self.wrap && self.capacity > 0 {
+                let overwritten = std::mem::replace(&mut self.records[self.written % self.capacity], record);
+                self.release_record(&overwritten);
+            }
+            else {
+                return Err(record);
+            }
+            self.written += 1;
+            Ok(self.written - 1)
+        }
+
+        // Gets a record by sequence number, None if it has since been overwritten
+        fn get_mut(&mut self, index : usize) -> Option<&mut ProfileRecord> {
+            if index >= self.written || self.written - index > self.records.len() {
+                return None;
+            }
+            self.records.get_mut(index % self.capacity)
+        }
+
+        // Iterates the records from oldest to newest
+        fn iter(&self) -> impl Iterator<Item = &ProfileRecord> {
+            let split = if self.written > self.records.len() { self.written % self.records.len() } else { 0 };
+            self.records[split..].iter().chain(self.records[..split].iter())
+        }
+    }
+
+    // The records of a single thread. Only the owning thread writes to the records, other threads
+    // only read them while holding the busy flag.
+    struct ThreadBuffer {
+        thread_id : u32,                         // The id of the owning thread
+        thread_name : Option<String>,            // The name of the owning thread
+        busy : AtomicBool,                       // If a thread is accessing the records
+        records : UnsafeCell<RecordBuffer>       // The profiling records
+    }
+    unsafe impl Sync for ThreadBuffer {}
+    unsafe impl Send for ThreadBuffer {}
+
+    impl ThreadBuffer {
+        // Only contended while a dump is copying the records
+        fn lock(&self) {
+            while self.busy.compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed).is_err() {
+                std::hint::spin_loop();
+            }
+        }
+
+        fn unlock(&self) {
+            self.busy.store(false, Ordering::SeqCst);
+        }
+    }
+
+    // The id of the active session, zero if profiling is not enabled
+    static ACTIVE_SESSION : AtomicUsize = AtomicUsize::new(0);
+    static NEXT_SESSION : AtomicUsize = AtomicUsize::new(1);
+    // The index of the next frame in the active session
+    static NEXT_FRAME : AtomicU64 = AtomicU64::new(0);
+
+    thread_local! {
+        // Set while the thread is recording, so allocations made while recording are not tracked
+        static RECORDING : Cell<bool> = const { Cell::new(false) };
+        // The buffer of the thread and the session it was registered with
+        // The buffer is None if the thread is filtered out of the session
+        static THREAD_BUFFER : RefCell<Option<(usize, Option<Arc<ThreadBuffer>>)>> = const { RefCell::new(None) };
+    }
+
+    // A source of timestamps for the profiler
+    pub trait ClockSource : Send + Sync {
+        // The current time in ticks, never decreasing
+        fn now(&self) -> i64;
+        // The number of ticks per second
+        fn frequency(&self) -> i64;
+        // Called when a session starts and before it is written, for clocks measuring their frequency
+        fn calibrate(&self) {}
+        // The UTC time, read alongside now() to anchor the session to the wall clock
+        fn wall_time(&self) -> SystemTime {
+            SystemTime::now()
+        }
+    }
+
+    // The OS monotonic clock, used unless another clock source is set
+    pub struct OsClock;
+
+    impl ClockSource for OsClock {
+        fn now(&self) -> i64 {
+            sys::StopWatch::get_time().0
+        }
+
+        fn frequency(&self) -> i64 {
+            sys::StopWatch::new().frequency()
+        }
+    }
+
+    // Reads the CPU timestamp counter, which is much cheaper than the OS clock. Only available on
+    // x86_64 CPUs with an invariant TSC, which ticks at a constant rate in all power states.
+    pub struct TscClock {
+        start_tsc : i64,        // The counter when the clock was created
+        start_nanos : i64,      // The OS clock in nanoseconds when the clock was created
+        frequency : AtomicI64   // The ticks per second, measured against the OS clock
+    }
+
+    impl TscClock {
+        // The TSC clock, None if the CPU has no invariant TSC so the OS clock should be used
+        pub fn get() -> Option<&'static TscClock> {
+            static TSC_CLOCK : OnceLock<Option<TscClock>> = OnceLock::new();
+            TSC_CLOCK.get_or_init(|| if has_invariant_tsc() { Some(TscClock::new()) } else { None }).as_ref()
+        }
+
+        fn new() -> TscClock {
+            let clock = TscClock {
+                start_tsc : read_tsc(),
+                start_nanos : os_nanoseconds(),
+                frequency : AtomicI64::new(0)
+            };
+
+            // Estimate the frequency until a session measures it over a longer time
+            let start = os_nanoseconds();
+            while os_nanoseconds() - start < 2_000_000 {
+                std::hint::spin_loop();
+            }
+            clock.calibrate();
+            clock
+        }
+    }
+
+    impl ClockSource for TscClock {
+        fn now(&self) -> i64 {
+            read_tsc()
+        }
+
+        fn frequency(&self) -> i64 {
+            self.frequency.load(Ordering::SeqCst)
+        }
+
+        // Measures the frequency from the creation of the clock, becoming more accurate over time
+        fn calibrate(&self) {
+            let ticks = read_tsc() - self.start_tsc;
+            let nanos = os_nanoseconds() - self.start_nanos;
+            if nanos > 0 {
+                let frequency = ticks as i128 * 1_000_000_000 / nanos as i128;
+                self.frequency.store(frequency as i64, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn os_nanoseconds() -> i64 {
+        sys::StopWatch::new().get_nanoseconds(&sys::TimePoint(0), &sys::StopWatch::get_time())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn read_tsc() -> i64 {
+        unsafe { std::arch::x86_64::_rdtsc() as i64 }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn read_tsc() -> i64 {
+        unreachable!("the TSC clock is only created on x86_64")
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn has_invariant_tsc() -> bool {
+        use std::arch::x86_64::__cpuid;
+
+        // The invariant TSC flag is bit 8 of edx in the advanced power management leaf
+        __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn has_invariant_tsc() -> bool {
+        false
+    }
+
+    // A clock that only moves when advanced, for tests that need exact timestamps
+    pub struct MockClock {
+        nanos : AtomicI64
+    }
+
+    impl MockClock {
+        pub const fn new() -> MockClock {
+            MockClock { nanos : AtomicI64::new(0) }
+        }
+
+        pub fn advance(&self, time : Duration) {
+            self.nanos.fetch_add(time.as_nanos() as i64, Ordering::SeqCst);
+        }
+
+        pub fn set(&self, time : Duration) {
+            self.nanos.store(time.as_nanos() as i64, Ordering::SeqCst);
+        }
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl ClockSource for MockClock {
+        fn now(&self) -> i64 {
+            self.nanos.load(Ordering::SeqCst)
+        }
+
+        fn frequency(&self) -> i64 {
+            1_000_000_000
+        }
+
+        // The mock time counts from the Unix epoch
+        fn wall_time(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_nanos(self.now().max(0) as u64)
+        }
+    }
+
+    // The clock of the active session, null for the OS clock so the default is a direct call
+    static CLOCK : AtomicPtr<&'static dyn ClockSource> = AtomicPtr::new(std::ptr::null_mut());
+
+    // The wall clock time of the clock of the active session
+    fn wall_time() -> SystemTime {
+        let clock = CLOCK.load(Ordering::Acquire);
+        if clock.is_null() {
+            SystemTime::now()
+        }
+        else {
+            unsafe { (*clock).wall_time() }
+        }
+    }
+
+    #[inline]
+    fn now() -> sys::TimePoint {
+        let clock = CLOCK.load(Ordering::Acquire);
+        if clock.is_null() {
+            sys::StopWatch::get_time()
+        }
+        else {
+            // Set clocks are never freed
+            sys::TimePoint(unsafe { (*clock).now() })
+        }
     }
 
     pub struct ProfileData {
+        clock : *mut &'static dyn ClockSource, // The clock of the next session, null for the OS clock
+        clocks : Vec<*mut &'static dyn ClockSource>, // Each clock set so far, boxed once as threads may still read it
         stopwatch : sys::StopWatch,
         start_time : sys::TimePoint,         // The start time of the profile
-        enabled : bool,               // If profiling is enabled
-        records : Vec<ProfileRecord>, // The profiling records
+        start_unix_nanos : i64,              // The wall clock time at the start, in nanoseconds since the Unix epoch
+        epoch_timestamps : bool,             // If timestamps are written from the Unix epoch rather than the start
+        process_name : Option<String>,       // The name of the process written to the profile
+        process_labels : Vec<String>,        // The labels of the process written to the profile
+        record_count : usize,                // The number of records each thread can hold
+        wrap_records : bool,                 // If the oldest records are overwritten when a thread is full
+        backtrace_depth : usize,             // The number of frames captured for each allocation
+        thread_filter : Option<ThreadFilter>, // Selects the threads recorded by the session, all if None
+        threads : Vec<Arc<ThreadBuffer>>,    // The buffers of the threads registered with the session
+        categories : CategoryFilter,         // The categories to record from the next session
+        session_categories : CategoryFilter, // The categories recorded by the current session
+        category_sites : Vec<&'static Category>, // The categories of the call sites resolved so far
     }
     impl ProfileData {
         pub fn new() -> ProfileData {
             ProfileData {
+                clock : std::ptr::null_mut(),
+                clocks : vec![],
                 stopwatch : sys::StopWatch::new(), 
-                start_time : sys::StopWatch::get_time(),  
-                enabled : false,
-                records : vec![]
+                start_time : now(),  
+                start_unix_nanos : 0,
+                epoch_timestamps : false,
+                process_name : None,
+                process_labels : vec![],
+                record_count : 0,
+                wrap_records : false,
+                backtrace_depth : 0,
+                thread_filter : None,
+                threads : vec![],
+                categories : CategoryFilter::default(),
+                session_categories : CategoryFilter::default(),
+                category_sites : vec![]
+            }
+        }
+
+        // Returns None if the thread is filtered out of the session
+        fn register_thread(&mut self) -> Option<Arc<ThreadBuffer>> {
+            let thread_name = std::thread::current().name().map(|n| n.to_string());
+            if let Some(ref filter) = self.thread_filter {
+                if !filter(thread_name.as_deref()) {
+                    return None;
+                }
+            }
+
+            let buffer = Arc::new(ThreadBuffer {
+                thread_id : sys::get_thread_id(),
+                thread_name,
+                busy : AtomicBool::new(false),
+                records : UnsafeCell::new(RecordBuffer {
+                    records : vec![],
+                    capacity : self.record_count,
+                    written : 0,
+                    wrap : self.wrap_records,
+                    backtrace_depth : self.backtrace_depth,
+                    dropped : DroppedRecords::default(),
+                    overflow_time : None,
+                    names : vec![],
+                    free_names : vec![],
+                    name_ids : HashMap::new(),
+                    scratch : String::new()
+                })
+            });
+            self.threads.push(buffer.clone());
+            Some(buffer)
+        }
+
+        // The pointer stored in CLOCK for the clock, reused when the same clock is set again
+        fn clock_ptr(&mut self, clock : Option<&'static dyn ClockSource>) -> *mut &'static dyn ClockSource {
+            let clock = match clock {
+                Some(clock) => clock,
+                None => return std::ptr::null_mut()
+            };
+            if let Some(&ptr) = self.clocks.iter().find(|&&ptr| std::ptr::addr_eq(unsafe { *ptr }, clock)) {
+                return ptr;
+            }
+            let ptr = Box::into_raw(Box::new(clock));
+            self.clocks.push(ptr);
+            ptr
+        }
+
+        fn register_category(&mut self, category : &'static Category) -> bool {
+            if category.state.load(Ordering::SeqCst) == CATEGORY_UNRESOLVED {
+                self.category_sites.push(category);
+            }
+            category.apply(&self.session_categories)
+        }
+    }
+
+    impl Default for ProfileData {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    // Calls the passed function with the records of the calling thread, registering a new buffer with
+    // the session if needed. Returns None if not profiling or if the thread is already recording.
+    fn with_thread_records<R>(f : impl FnOnce(&mut RecordBuffer) -> Option<R>) -> Option<R> {
+        with_session_records(ACTIVE_SESSION.load(Ordering::Relaxed), f)
+    }
+
+    // As with_thread_records, but only calls the function while the given session is active
+    fn with_session_records<R>(session : usize, f : impl FnOnce(&mut RecordBuffer) -> Option<R>) -> Option<R> {
+        if session == 0 {
+            return None;
+        }
+
+        // Flag the thread as recording (fails during thread shutdown)
+        match RECORDING.try_with(|r| r.replace(true)) {
+            Ok(false) => {}
+            _ => return None
+        }
+        let _recording = RecordingFlag;
+
+        THREAD_BUFFER.try_with(|entry| {
+            let mut entry = entry.borrow_mut();
+
+            // Register a new buffer on first use in the session
+            if entry.as_ref().map(|e| e.0) != Some(session) {
+                let mut profile = get_profile_no_recurse().ok()?;
+                if ACTIVE_SESSION.load(Ordering::SeqCst) != session {
+                    return None;
+                }
+                *entry = Some((session, profile.register_thread()));
+            }
+
+            let buffer = entry.as_ref()?.1.as_ref()?;
+            buffer.lock();
+            let _unlock = BufferUnlock(buffer);
+            if ACTIVE_SESSION.load(Ordering::SeqCst) != session {
+                return None;
+            }
+            f(unsafe { &mut *buffer.records.get() })
+        }).ok().flatten()
+    }
+
+    // Clears the recording flag of the thread when dropped, including when formatting a tag name panics
+    struct RecordingFlag;
+
+    impl Drop for RecordingFlag {
+        fn drop(&mut self) {
+            let _ = RECORDING.try_with(|r| r.set(false));
+        }
+    }
+
+    // Unlocks the buffer when dropped, so a panic while recording can't leave the buffer locked
+    struct BufferUnlock<'a>(&'a ThreadBuffer);
+
+    impl Drop for BufferUnlock<'_> {
+        fn drop(&mut self) {
+            self.0.unlock();
+        }
+    }
+
+    fn add_record(record : ProfileRecord) -> Option<usize> {
+        with_thread_records(|records| records.add_record(record))
+    }
+
+    // The categories recorded by a session, all categories are recorded unless filtered
+    #[derive(Clone, Default)]
+    struct CategoryFilter {
+        only : Option<Vec<String>>, // If set, only these categories are recorded
+        disabled : Vec<String>      // The categories that are never recorded
+    }
+
+    impl CategoryFilter {
+        fn is_enabled(&self, name : &str) -> bool {
+            self.only.as_ref().is_none_or(|only| only.iter().any(|c| c == name)) &&
+                !self.disabled.iter().any(|c| c == name)
+        }
+    }
+
+    const CATEGORY_UNRESOLVED : u8 = 0;
+    const CATEGORY_DISABLED : u8 = 1;
+    const CATEGORY_ENABLED : u8 = 2;
+
+    // The category of a call site, declared as a static by the profile macros. The filter is resolved
+    // for each site on first use and updated when a session starts, so checking it is a single load.
+    pub struct Category {
+        name : &'static str,
+        state : AtomicU8
+    }
+
+    impl Category {
+        pub const fn new(name : &'static str) -> Category {
+            Category { name, state : AtomicU8::new(CATEGORY_UNRESOLVED) }
+        }
+
+        pub fn name(&self) -> &'static str {
+            self.name
+        }
+
+        #[inline]
+        pub fn enabled(&'static self) -> bool {
+            match self.state.load(Ordering::Relaxed) {
+                CATEGORY_ENABLED => true,
+                CATEGORY_DISABLED => false,
+                _ => self.resolve()
             }
         }
 
-        fn add_record(&mut self, record : ProfileRecord) -> Option<usize> {
-            if !self.enabled || 
-               self.records.len() >= self.records.capacity()
-            {
-                return None;
+        #[cold]
+        fn resolve(&'static self) -> bool {
+            // Flag the thread as recording, so registering the site is not tracked as an allocation
+            match RECORDING.try_with(|r| r.replace(true)) {
+                Ok(false) => {}
+                _ => return false
             }
-            self.records.push(record);
-            return Some(self.records.len() - 1);
-        }            
+            let enabled = get_profile_no_recurse().map(|mut profile| profile.register_category(self)).unwrap_or(false);
+            RECORDING.with(|r| r.set(false));
+            enabled
+        }
+
+        fn apply(&self, filter : &CategoryFilter) -> bool {
+            let enabled = filter.is_enabled(self.name);
+            self.state.store(if enabled { CATEGORY_ENABLED } else { CATEGORY_DISABLED }, Ordering::SeqCst);
+            enabled
+        }
+    }
+
+    #[inline]
+    fn category_enabled(category : Option<&'static Category>) -> bool {
+        category.is_none_or(|c| c.enabled())
     }
 
+    // A scope dropped on another thread than the one it began on, as when held across an await, is
+    // left as a begin event, as only the owning thread writes to its records
     pub struct ProfileScope {
-        index : Option<usize>,
-        time : sys::TimePoint
+        index : Option<usize>,  // The sequence number of the begin record
+        session : usize,        // The session the begin record was added to
+        buffer : usize          // The address of the thread records the begin record was added to
     }
 
     impl ProfileScope {
-        pub fn new(name: &'static str) -> ProfileScope {
-            let thread_id = sys::get_thread_id();            
+        pub fn new(category : Option<&'static Category>, name: &'static str) -> ProfileScope {
+            ProfileScope::with_tag(category, |_| TagType::Begin(Tag::Static(name), None))
+        }
+
+        pub fn new_with_args(category : Option<&'static Category>, name: &'static str, args: &[(&'static str, Arg)]) -> ProfileScope {
+            ProfileScope::with_tag(category, |records| TagType::Begin(Tag::Static(name), records.add_args(args)))
+        }
 
+        // Tag with a name built at runtime, copied into the session
+        pub fn new_dynamic(category : Option<&'static Category>, name: &str) -> ProfileScope {
+            ProfileScope::with_tag(category, |records| TagType::Begin(records.intern(name), None))
+        }
+
+        pub fn new_fmt(category : Option<&'static Category>, args: fmt::Arguments) -> ProfileScope {
+            ProfileScope::with_tag(category, |records| TagType::Begin(records.intern_fmt(args), None))
+        }
+
+        fn with_tag(category : Option<&'static Category>, tag : impl FnOnce(&mut RecordBuffer) -> TagType) -> ProfileScope {
             // Start as a begin tag
-            let mut ret = ProfileScope { index : None, time : sys::StopWatch::get_time() };            
-            if let Ok(ref mut profile) = get_profile() {
-                ret.index = profile.add_record(ProfileRecord { time : ret.time, thread_id, tag : TagType::Begin(name) });
-            }            
-            ret
+            let session = ACTIVE_SESSION.load(Ordering::Relaxed);
+            if !category_enabled(category) {
+                return ProfileScope { index : None, session, buffer : 0 };
+            }
+            let begin = with_session_records(session, |records| {
+                let tag = tag(records);
+                let time = now();
+                let index = records.add_scope_record(ProfileRecord { time, tag, category })?;
+                Some((index, records as *const RecordBuffer as usize))
+            });
+            match begin {
+                Some((index, buffer)) => ProfileScope { index : Some(index), session, buffer },
+                None => ProfileScope { index : None, session, buffer : 0 }
+            }
         }
     }
 
     impl Drop for ProfileScope {
         fn drop(&mut self) {
             if let Some(index) = self.index {
-                if let Ok(ref mut profile) = get_profile() {
-                    let profile = profile.deref_mut();                    
-                    if index < profile.records.len() {
-                        let record = &mut profile.records[index];
-                        if let TagType::Begin(name) = record.tag {
-                            if self.time == record.time {
-                                // If the time is different, it must have started in a different profile session
-                                // Change the tag type to complete
-                                let duration = profile.stopwatch.get_milliseconds(&record.time, &sys::StopWatch::get_time());
-                                record.tag = TagType::Complete(name, duration);
-                            }
+                let end_time = now();
+                // A scope begun in an earlier session is discarded, its records were already written
+                with_session_records(self.session, |records| {
+                    if records as *const RecordBuffer as usize != self.buffer {
+                        return None;
+                    }
+
+                    // Change the tag type to complete
+                    let record = records.get_mut(index)?;
+                    if let TagType::Begin(..) = record.tag {
+                        if let TagType::Begin(name, args) = std::mem::replace(&mut record.tag, TagType::End) {
+                            record.tag = TagType::Complete(name, args, end_time);
                         }
                     }
-                }
+                    Some(())
+                });
             }            
         }
     }
 
+    pub fn type_name_of<T>(_ : T) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    // Gets the enclosing function path from the type name of a function declared in it,
+    // keeping only the last segments of the path if segments is not zero
+    pub fn function_name(type_name : &'static str, segments : usize) -> &'static str {
+        let mut name = type_name.strip_suffix("::f").unwrap_or(type_name);
+        while let Some(outer) = name.strip_suffix("::{{closure}}") {
+            name = outer;
+        }
+        if segments == 0 {
+            return name;
+        }
+
+        // Find the start of the last segments, ignoring separators in generic arguments
+        let bytes = name.as_bytes();
+        let mut depth = 0;
+        let mut found = 0;
+        let mut i = bytes.len();
+        while i > 1 {
+            i -= 1;
+            match bytes[i] {
+                b'>' => depth += 1,
+                b'<' => depth -= 1,
+                b':' if depth == 0 && bytes[i - 1] == b':' => {
+                    found += 1;
+                    if found == segments {
+                        return &name[i + 1..];
+                    }
+                    i -= 1;
+                }
+                _ => {}
+            }
+        }
+        name
+    }
+
+    // Wraps a future, profiling each poll of the future as a scope
+    pub struct ProfileFuture<F> {
+        category : Option<&'static Category>,
+        name : &'static str,
+        future : F
+    }
+
+    impl<F> ProfileFuture<F> {
+        pub fn new(category : Option<&'static Category>, name : &'static str, future : F) -> ProfileFuture<F> {
+            ProfileFuture { category, name, future }
+        }
+    }
+
+    impl<F : Future> Future for ProfileFuture<F> {
+        type Output = F::Output;
+
+        fn poll(self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<F::Output> {
+            let _profile_guard = ProfileScope::new(self.category, self.name);
+
+            // The future is never moved out of the pinned wrapper
+            unsafe { self.map_unchecked_mut(|s| &mut s.future) }.poll(cx)
+        }
+    }
+
     pub struct MemTrackAllocator;
     static TRACK_ALLOCS : AtomicBool = AtomicBool::new(false);
     impl MemTrackAllocator
@@ -349,189 +2120,775 @@ pub mod internal {
     unsafe impl GlobalAlloc for MemTrackAllocator {
         unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
             if MemTrackAllocator::get_mem_tracking() {
-                if let Ok(ref mut profile) = get_profile_no_recurse() {                
-                    //let bt = Backtrace::new_unresolved();
-                    //println!("Stack {:?}", bt);
-                    backtrace::trace_unsynchronized(|frame| { true });
-                    //backtrace::trace(|frame| { true });
+                with_thread_records(|records| {
+                    // Allocations made here are not tracked, as the thread is flagged as recording
+                    let mut stack = None;
+                    if records.backtrace_depth > 0 {
+                        let mut frames = Vec::with_capacity(records.backtrace_depth);
+                        backtrace::trace_unsynchronized(|frame| {
+                            frames.push(frame.ip() as usize);
+                            frames.len() < records.backtrace_depth
+                        });
+                        stack = Some(frames.into_boxed_slice());
+                    }
 
-                    let time = sys::StopWatch::get_time();
-                    let thread_id = sys::get_thread_id();
-                    profile.add_record(ProfileRecord { thread_id, tag : TagType::Allocate(_layout.size()), time });
-                }            
+                    let time = now();
+                    records.add_record(ProfileRecord { tag : TagType::Allocate(_layout.size(), stack), time, category : None })
+                });
             }
             System.alloc(_layout) 
         }
 
         unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
             if MemTrackAllocator::get_mem_tracking() {
-                if let Ok(ref mut profile) = get_profile_no_recurse() {                                
-                    let time = sys::StopWatch::get_time();
-                    let thread_id = sys::get_thread_id();
-                    profile.add_record(ProfileRecord { thread_id, tag : TagType::Deallocate(_layout.size()), time });
-                }             
+                with_thread_records(|records| {
+                    let time = now();
+                    records.add_record(ProfileRecord { tag : TagType::Deallocate(_layout.size()), time, category : None })
+                });
             }
             System.dealloc(_ptr, _layout)
         }
     }
 
-    pub fn profile_begin(tag : &'static str)
+    pub fn profile_begin(category : Option<&'static Category>, tag : &'static str)
     {
-        let thread_id = sys::get_thread_id();
-        if let Ok(ref mut profile) = get_profile() {
-            profile.add_record(ProfileRecord { thread_id, tag : TagType::Begin(tag), time : sys::StopWatch::get_time() });
-        }            
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::Begin(Tag::Static(tag), None), time : now(), category });
+        }
     }
 
-    pub fn profile_end()
+    pub fn profile_begin_with_args(category : Option<&'static Category>, tag : &'static str, args : &[(&'static str, Arg)])
     {
-        let time = sys::StopWatch::get_time(); // Always get time as soon as possible
-        let thread_id = sys::get_thread_id();
-        if let Ok(ref mut profile) = get_profile() {
-            profile.add_record(ProfileRecord { thread_id, tag : TagType::End, time });
-        }            
+        if category_enabled(category) {
+            with_thread_records(|records| {
+                let args = records.add_args(args);
+                records.add_record(ProfileRecord { tag : TagType::Begin(Tag::Static(tag), args), time : now(), category })
+            });
+        }
     }
 
-    pub fn begin(record_count : usize) {
-        if let Ok(ref mut profile) = get_profile() {
-            // Abort if already enabled
-            if profile.enabled {
-                return;
+    // Begin with a tag name built at runtime, copied into the session
+    pub fn profile_begin_dynamic(category : Option<&'static Category>, name : &str)
+    {
+        if category_enabled(category) {
+            with_thread_records(|records| {
+                let tag = records.intern(name);
+                records.add_record(ProfileRecord { tag : TagType::Begin(tag, None), time : now(), category })
+            });
+        }
+    }
+
+    pub fn profile_begin_fmt(category : Option<&'static Category>, args : fmt::Arguments)
+    {
+        if category_enabled(category) {
+            with_thread_records(|records| {
+                let tag = records.intern_fmt(args);
+                records.add_record(ProfileRecord { tag : TagType::Begin(tag, None), time : now(), category })
+            });
+        }
+    }
+
+    pub fn profile_instant(category : Option<&'static Category>, tag : &'static str, scope : InstantScope)
+    {
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::Instant(Tag::Static(tag), scope), time : now(), category });
+        }
+    }
+
+    pub fn profile_counter(category : Option<&'static Category>, tag : &'static str, value : f64)
+    {
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::Counter(Tag::Static(tag), CounterValues::Single(value)), time : now(), category });
+        }
+    }
+
+    pub fn profile_counter_series(category : Option<&'static Category>, tag : &'static str, series : &[(&'static str, f64)])
+    {
+        if category_enabled(category) {
+            let time = now();
+            with_thread_records(|records| {
+                records.add_record(ProfileRecord { tag : TagType::Counter(Tag::Static(tag), CounterValues::Series(series.into())), time, category })
+            });
+        }
+    }
+
+    // Async spans can begin and end on different threads, they are paired by name and id
+    pub fn profile_async_begin(category : Option<&'static Category>, tag : &'static str, id : u64)
+    {
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::AsyncBegin(Tag::Static(tag), id), time : now(), category });
+        }
+    }
+
+    pub fn profile_async_end(category : Option<&'static Category>, tag : &'static str, id : u64)
+    {
+        let time = now(); // Always get time as soon as possible
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::AsyncEnd(Tag::Static(tag), id), time, category });
+        }
+    }
+
+    // Call from inside a scope to link it to the scopes with the other phases of the same flow id.
+    // The phases are matched by category and id, those without a category are written as "flow".
+    pub fn profile_flow(category : Option<&'static Category>, phase : FlowPhase, id : u64)
+    {
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::Flow(phase, id), time : now(), category });
+        }
+    }
+
+    // Marks the boundary between two frames, usually called once per frame from the main loop
+    pub fn profile_frame()
+    {
+        let time = now();
+        if ACTIVE_SESSION.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let frame = NEXT_FRAME.fetch_add(1, Ordering::Relaxed);
+        add_record(ProfileRecord { tag : TagType::Frame(frame), time, category : None });
+    }
+
+    // The end of a begin with a category must be given the same category, so both are filtered together
+    pub fn profile_end(category : Option<&'static Category>)
+    {
+        let time = now(); // Always get time as soon as possible
+        if category_enabled(category) {
+            add_record(ProfileRecord { tag : TagType::End, time, category });
+        }
+    }
+
+    // What happens to the records of a thread once its buffer is full
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum OverflowPolicy {
+        Truncate,   // Later records are dropped
+        Wrap        // The oldest records are overwritten, keeping the latest as a flight recorder
+    }
+
+    // Selects the threads to record by their name
+    pub type ThreadFilter = Arc<dyn Fn(Option<&str>) -> bool + Send + Sync>;
+
+    // The settings of a profile session. The categories and clock source only apply to the session,
+    // others use those set with set_categories and set_clock_source.
+    #[derive(Clone)]
+    pub struct ProfileConfig {
+        record_count : usize,                   // The number of records each thread can hold
+        overflow : OverflowPolicy,
+        track_memory : bool,                    // If allocations are recorded by the memory allocator
+        backtrace_depth : usize,                // The number of frames captured for each allocation
+        categories : Option<Vec<String>>,       // Only these categories are recorded if set
+        clock : Option<Option<&'static dyn ClockSource>>, // The clock source if set, None in it for the OS clock
+        thread_filter : Option<ThreadFilter>,
+    }
+
+    impl ProfileConfig {
+        // Each thread holds up to record_count records, allocated as the thread records
+        pub fn new(record_count : usize) -> ProfileConfig {
+            ProfileConfig {
+                record_count,
+                overflow : OverflowPolicy::Truncate,
+                track_memory : true,
+                backtrace_depth : 0,
+                categories : None,
+                clock : None,
+                thread_filter : None
             }
+        }
+
+        pub fn overflow(mut self, overflow : OverflowPolicy) -> Self {
+            self.overflow = overflow;
+            self
+        }
+
+        pub fn track_memory(mut self, track_memory : bool) -> Self {
+            self.track_memory = track_memory;
+            self
+        }
+
+        // Captures the return addresses of each tracked allocation, written as the allocation stack
+        pub fn backtrace_depth(mut self, depth : usize) -> Self {
+            self.backtrace_depth = depth;
+            self
+        }
+
+        pub fn categories(mut self, categories : &[&str]) -> Self {
+            self.categories = Some(categories.iter().map(|c| c.to_string()).collect());
+            self
+        }
+
+        pub fn clock_source(mut self, clock : Option<&'static dyn ClockSource>) -> Self {
+            self.clock = Some(clock);
+            self
+        }
+
+        // Only records the threads for which the filter returns true, given the thread name
+        pub fn thread_filter(mut self, filter : impl Fn(Option<&str>) -> bool + Send + Sync + 'static) -> Self {
+            self.thread_filter = Some(Arc::new(filter));
+            self
+        }
+    }
+
+    pub fn begin_with_config(config : ProfileConfig) -> Result<(), ProfilerError> {
+        let mut profile = lock_profile()?;
+        let profile = &mut *profile;
+        if ACTIVE_SESSION.load(Ordering::SeqCst) != 0 {
+            return Err(ProfilerError::AlreadyRunning);
+        }
+
+        // Each thread allocates its records as it records in the session
+        if Layout::array::<ProfileRecord>(config.record_count).is_err() {
+            return Err(ProfilerError::Overflow);
+        }
+
+        profile.threads.clear();
+        profile.record_count = config.record_count;
+        profile.wrap_records = config.overflow == OverflowPolicy::Wrap;
+        profile.backtrace_depth = config.backtrace_depth;
+        profile.thread_filter = config.thread_filter;
+
+        // Switch to the clock of the new session
+        let clock = match config.clock {
+            Some(clock) => profile.clock_ptr(clock),
+            None => profile.clock
+        };
+        CLOCK.store(clock, Ordering::Release);
+        calibrate_clock(profile);
+
+        // Anchor the start to the wall clock, so the trace can be matched with other logs
+        profile.start_time = now();
+        profile.start_unix_nanos = wall_time().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as i64);
+        NEXT_FRAME.store(0, Ordering::SeqCst);
+
+        // Apply the category filter to the call sites seen in earlier sessions
+        profile.session_categories = match config.categories {
+            Some(categories) => CategoryFilter { only : Some(categories), disabled : vec![] },
+            None => profile.categories.clone()
+        };
+        for category in profile.category_sites.iter() {
+            category.apply(&profile.session_categories);
+        }
+
+        ACTIVE_SESSION.store(NEXT_SESSION.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+        MemTrackAllocator::set_mem_tracking(config.track_memory);
+        Ok(())
+    }
+
+    pub fn begin(record_count : usize) -> Result<(), ProfilerError> {
+        begin_with_config(ProfileConfig::new(record_count))
+    }
+
+    // Each thread keeps its last record_count records, overwriting the oldest
+    pub fn begin_flight_recorder(record_count : usize) -> Result<(), ProfilerError> {
+        begin_with_config(ProfileConfig::new(record_count).overflow(OverflowPolicy::Wrap))
+    }
+
+    // Formats a nanosecond count as fractional microseconds, the time unit of trace events
+    struct Micros(i64);
+
+    impl fmt::Display for Micros {
+        fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+            let sign = if self.0 < 0 { "-" } else { "" };
+            let nanos = self.0.unsigned_abs();
+            write!(f, "{}{}.{:03}", sign, nanos / 1000, nanos % 1000)
+        }
+    }
+
+    // Formats nanoseconds since the Unix epoch as an ISO 8601 UTC time
+    struct Utc(i64);
+
+    impl fmt::Display for Utc {
+        fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+            let secs = self.0.div_euclid(1_000_000_000);
+            let nanos = self.0.rem_euclid(1_000_000_000);
+            let (days, day_secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+            // Convert the days to a civil date (http://howardhinnant.github.io/date_algorithms.html)
+            let z = days + 719_468;
+            let era = z.div_euclid(146_097);
+            let doe = z.rem_euclid(146_097);
+            let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+            let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+            let mp = (5 * doy + 2) / 153;
+            let day = doy - (153 * mp + 2) / 5 + 1;
+            let month = if mp < 10 { mp + 3 } else { mp - 9 };
+            let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+            write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z", year, month, day,
+                day_secs / 3600, day_secs / 60 % 60, day_secs % 60, nanos)
+        }
+    }
+
+    fn to_duration(nanos : i64) -> Duration {
+        Duration::from_nanos(nanos.max(0) as u64)
+    }
 
-            profile.records.clear();
-            profile.records.reserve(record_count);
-            profile.start_time = sys::StopWatch::get_time();
+    fn write_json_f64(out : &mut String, value : f64) -> fmt::Result {
+        use std::fmt::Write;
 
-            profile.enabled = true;
-            MemTrackAllocator::set_mem_tracking(true);
-        }            
+        if value.is_finite() {
+            write!(out, "{}", value)
+        }
+        else {
+            // Json has no NaN or infinity
+            write!(out, "\"{}\"", value)
+        }
     }
 
     fn clean_json_str<'a>(io_str : &'a str, str_buffer : &'a mut String) -> &'a str {
+        use std::fmt::Write;
+
         // Check if there are any characters to replace
-        if io_str.find(|c: char| (c == '\\') || (c == '"')) == None {
+        if !io_str.contains(|c : char| c == '\\' || c == '"' || c < ' ') {
             return io_str;
         }
 
-        // Escape json protected characters (not fast, but should be rare)
-        *str_buffer = io_str.replace('\\', "\\\\").replace('"', "\\\"");
-        return str_buffer;
+        // Escape json protected and control characters (not fast, but should be rare)
+        str_buffer.clear();
+        for c in io_str.chars() {
+            match c {
+                '\\' => str_buffer.push_str("\\\\"),
+                '"' => str_buffer.push_str("\\\""),
+                '\n' => str_buffer.push_str("\\n"),
+                '\r' => str_buffer.push_str("\\r"),
+                '\t' => str_buffer.push_str("\\t"),
+                c if c < ' ' => { let _ = write!(str_buffer, "\\u{:04x}", c as u32); }
+                c => str_buffer.push(c)
+            }
+        }
+        str_buffer
     }
 
-    pub fn end_to_file(filename : &str) -> io::Result<()> {
-        MemTrackAllocator::set_mem_tracking(false);
+    pub fn end_to_file(filename : &str) -> Result<ProfileStats, ProfilerError> {
+        if ACTIVE_SESSION.load(Ordering::SeqCst) == 0 {
+            return Err(ProfilerError::NotStarted);
+        }
         end(&mut BufWriter::new(std::fs::File::create(filename)?))
     }
 
-    pub fn end(w : &mut dyn Write) -> io::Result<()> {
+    pub fn end(w : &mut dyn Write) -> Result<ProfileStats, ProfilerError> {
+        let mut profile = lock_profile()?;
+        let profile = &mut *profile;
+        if ACTIVE_SESSION.load(Ordering::SeqCst) == 0 {
+            return Err(ProfilerError::NotStarted);
+        }
+
+        // Only stop tracking memory once the session is sure to end, so a failed end keeps recording
         MemTrackAllocator::set_mem_tracking(false);
+
+        ACTIVE_SESSION.store(0, Ordering::SeqCst);
+
+        // Wait for any thread still writing a record, after which the buffers are no longer written to
+        let buffers = std::mem::take(&mut profile.threads);
+        for buffer in buffers.iter() {
+            buffer.lock();
+            buffer.unlock();
+        }
+        let threads : Vec<_> = buffers.iter().map(|b| (&**b, unsafe { &*b.records.get() })).collect();
+        calibrate_clock(profile);
+        let ret = write_records(profile, &threads, w);
+
+        // Each thread keeps its buffer until it next records, so free the records now they are written
+        for buffer in buffers.iter() {
+            buffer.lock();
+            unsafe { &mut *buffer.records.get() }.free();
+            buffer.unlock();
+        }
+        Ok(ret?)
+    }
+
+    pub fn set_process_name(name : &str) {
+        if let Ok(ref mut profile) = get_profile() {
+            profile.process_name = Some(name.to_string());
+        }
+    }
+
+    pub fn set_process_labels(labels : &[&str]) {
+        if let Ok(ref mut profile) = get_profile() {
+            profile.process_labels = labels.iter().map(|l| l.to_string()).collect();
+        }
+    }
+
+    // Writes the timestamps as time since the Unix epoch, rather than time since the profile started
+    pub fn set_epoch_timestamps(enabled : bool) {
+        if let Ok(ref mut profile) = get_profile() {
+            profile.epoch_timestamps = enabled;
+        }
+    }
+
+    // Timestamps are taken from the clock from the next session, the OS clock if None.
+    // A pointer to each clock set is kept for the life of the process, as threads may still be reading it.
+    pub fn set_clock_source(clock : Option<&'static dyn ClockSource>) {
+        if let Ok(ref mut profile) = get_profile() {
+            profile.clock = profile.clock_ptr(clock);
+        }
+    }
+
+
+    // Only the given categories are recorded from the next session, events without a category are always recorded
+    pub fn set_categories(categories : &[&str]) {
         if let Ok(ref mut profile) = get_profile() {
-            // Abort if already enabled
-            if !profile.enabled {
-                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            profile.categories = CategoryFilter {
+                only : Some(categories.iter().map(|c| c.to_string()).collect()),
+                disabled : vec![]
+            };
+        }
+    }
+
+    // Records the category from the next session
+    pub fn enable_category(category : &str) {
+        if let Ok(ref mut profile) = get_profile() {
+            let filter = &mut profile.categories;
+            filter.disabled.retain(|c| c != category);
+            if let Some(ref mut only) = filter.only {
+                if !only.iter().any(|c| c == category) {
+                    only.push(category.to_string());
+                }
             }
+        }
+    }
 
-            profile.enabled = false;
+    // Stops recording the category from the next session
+    pub fn disable_category(category : &str) {
+        if let Ok(ref mut profile) = get_profile() {
+            let filter = &mut profile.categories;
+            if let Some(ref mut only) = filter.only {
+                only.retain(|c| c != category);
+            }
+            else if !filter.disabled.iter().any(|c| c == category) {
+                filter.disabled.push(category.to_string());
+            }
+        }
+    }
 
-            // DT_TODO: Parhaps use thread::current().name() ?
-            let mut thread_stack = HashMap::new();
-            thread_stack.insert(sys::get_thread_id(), Tags { index : 0, tags : vec!()});
+    pub fn dump_to_file(filename : &str) -> Result<ProfileStats, ProfilerError> {
+        if ACTIVE_SESSION.load(Ordering::SeqCst) == 0 {
+            return Err(ProfilerError::NotStarted);
+        }
+        dump(&mut BufWriter::new(std::fs::File::create(filename)?))
+    }
 
-            let mut first : bool = true;
-            let mut clean_buffer : String = String::new();
-            let mut extra_buffer : String = String::new();
+    // Writes the records currently held by the session without stopping it
+    pub fn dump(w : &mut dyn Write) -> Result<ProfileStats, ProfilerError> {
+        let mut profile = lock_profile()?;
+        let profile = &mut *profile;
+        if ACTIVE_SESSION.load(Ordering::SeqCst) == 0 {
+            return Err(ProfilerError::NotStarted);
+        }
 
-            w.write(b"{\"traceEvents\":[\n")?;
-            for entry in profile.records.iter()
-            {
-                // Assign a unique index to each thread                
-                let new_id = thread_stack.len();
-                let stack = thread_stack.entry(entry.thread_id).or_insert(Tags { index : new_id, tags : vec!()});
-
-                let tag;
-                let type_tag;
-                extra_buffer.clear();
-                match entry.tag {
-                    TagType::Begin(s) => {
-                        type_tag = "B"; 
-                        tag = s; 
-                        stack.tags.push(tag)
-                    },
-                    TagType::End => {
-                        type_tag = "E"; 
-                        if let Some(stack_tag) = stack.tags.pop() {
-                            tag = stack_tag;
+        // Do not track the allocations made while dumping
+        let was_recording = RECORDING.with(|r| r.replace(true));
+        calibrate_clock(profile);
+
+        // Copy the records of each thread, briefly stalling the thread if it is recording
+        let copies : Vec<_> = profile.threads.iter().map(|buffer| {
+            buffer.lock();
+            let records = unsafe { &*buffer.records.get() }.snapshot();
+            buffer.unlock();
+            (&**buffer, records)
+        }).collect();
+        let threads : Vec<_> = copies.iter().map(|(buffer, records)| (*buffer, records)).collect();
+        let ret = write_records(profile, &threads, w);
+
+        RECORDING.with(|r| r.set(was_recording));
+        Ok(ret?)
+    }
+
+    // Calibrates the clock of the session, and takes its frequency for converting the timestamps
+    fn calibrate_clock(profile : &mut ProfileData) {
+        let clock = CLOCK.load(Ordering::Acquire);
+        profile.stopwatch = if clock.is_null() {
+            sys::StopWatch::new()
+        }
+        else {
+            let clock = unsafe { *clock };
+            clock.calibrate();
+            sys::StopWatch::with_frequency(clock.frequency())
+        };
+    }
+
+    fn write_records(profile : &ProfileData, threads : &[(&ThreadBuffer, &RecordBuffer)], w : &mut dyn Write) -> io::Result<ProfileStats> {
+        // Merge the thread records by time (stable, so thread order is kept for equal times). Threads are
+        // keyed by their buffer, as the OS can reuse the id of an exited thread within a session.
+        let mut records = vec!();
+        for (buffer_index, (_, thread_records)) in threads.iter().enumerate() {
+            records.extend(thread_records.iter().map(|r| (buffer_index, *thread_records, r)));
+        }
+        records.sort_by_key(|(_, _, r)| r.time);
+
+        // The frame boundaries, time spent in a tag is split across the frames it overlaps
+        let frame_starts : Vec<(u64, sys::TimePoint)> = records.iter().filter_map(|(_, _, r)| match r.tag {
+            TagType::Frame(frame) => Some((frame, r.time)),
+            _ => None
+        }).collect();
+        let mut frame_tags : Vec<HashMap<&str, i64>> = vec![HashMap::new(); frame_starts.len().saturating_sub(1)];
+        let mut add_frame_time = |tag, begin : &sys::TimePoint, end : &sys::TimePoint| {
+            let mut frame = frame_starts.partition_point(|(_, start)| start <= begin).saturating_sub(1);
+            while frame + 1 < frame_starts.len() && frame_starts[frame].1 < *end {
+                let from = (*begin).max(frame_starts[frame].1);
+                let to = (*end).min(frame_starts[frame + 1].1);
+                if from < to {
+                    *frame_tags[frame].entry(tag).or_insert(0) += profile.stopwatch.get_nanoseconds(&from, &to);
+                }
+                frame += 1;
+            }
+        };
+
+        // The calling thread comes first, its buffer being the latest one with its id
+        let thread_id = sys::get_thread_id();
+        let calling_buffer = threads.iter().rposition(|(buffer, _)| buffer.thread_id == thread_id).unwrap_or(usize::MAX);
+        let mut thread_stack = HashMap::new();
+        thread_stack.insert(calling_buffer, Tags { index : 0, tags : vec!()});
+
+        let pid = std::process::id();
+        let time_origin = if profile.epoch_timestamps { profile.start_unix_nanos } else { 0 };
+        let mut first : bool = true;
+        let mut clean_buffer : String = String::new();
+        let mut extra_buffer : String = String::new();
+        let mut category_buffer : String = String::new();
+
+        w.write_all(b"{\"traceEvents\":[\n")?;
+        for (buffer_index, thread_records, entry) in records.iter()
+        {
+            // Assign a unique index to each thread                
+            let new_id = thread_stack.len();
+            let stack = thread_stack.entry(*buffer_index).or_insert(Tags { index : new_id, tags : vec!()});
+            let tag;
+            let type_tag;
+            let mut category = entry.category.map(|c| c.name());
+            extra_buffer.clear();
+            match entry.tag {
+                TagType::Begin(ref t, ref args) => {
+                    type_tag = "B"; 
+                    tag = thread_records.tag_name(t); 
+                    stack.tags.push((tag, entry.time));
+                    thread_records.write_args(args, &mut extra_buffer);
+                },
+                TagType::End => {
+                    type_tag = "E"; 
+                    if let Some((stack_tag, begin_time)) = stack.tags.pop() {
+                        tag = stack_tag;
+                        add_frame_time(tag, &begin_time, &entry.time);
+                    }
+                    else {
+                        tag = "Unknown";
+                    }
+                }
+                TagType::Complete(ref t, ref args, end_time) => {
+                    type_tag = "X"; 
+                    tag = thread_records.tag_name(t);
+                    add_frame_time(tag, &entry.time, &end_time);
+                    extra_buffer = format!(",\"dur\":{}", Micros(profile.stopwatch.get_nanoseconds(&entry.time, &end_time)));
+                    thread_records.write_args(args, &mut extra_buffer);
+                }
+                TagType::Allocate(a, ref stack) => {
+                    type_tag = "O"; 
+                    tag = "Allocate";
+                    extra_buffer = format!(",\"id\":0,\"args\":{{\"snapshot\":{{\"amount\":{}}}", a);
+                    if let Some(stack) = stack {
+                        extra_buffer.push_str(",\"stack\":[");
+                        for (i, ip) in stack.iter().enumerate() {
+                            if i > 0 {
+                                extra_buffer.push(',');
+                            }
+                            extra_buffer.push_str(&format!("\"{:#x}\"", ip));
+                        }
+                        extra_buffer.push(']');
+                    }
+                    extra_buffer.push('}');
+                }                                        
+                TagType::Deallocate(a) => {
+                    type_tag = "O"; 
+                    tag = "Deallocate";
+                    extra_buffer = format!(",\"id\":1,\"args\":{{\"snapshot\":{{\"amount\":{}}}}}", a);                        
+                }                                        
+                TagType::Counter(ref t, ref values) => {
+                    type_tag = "C";
+                    tag = thread_records.tag_name(t);
+                    extra_buffer.push_str(",\"args\":{");
+                    match values {
+                        CounterValues::Single(value) => {
+                            extra_buffer.push_str("\"value\":");
+                            let _ = write_json_f64(&mut extra_buffer, *value);
                         }
-                        else {
-                            tag = "Unknown";
+                        CounterValues::Series(series) => {
+                            for (i, (name, value)) in series.iter().enumerate() {
+                                if i > 0 {
+                                    extra_buffer.push(',');
+                                }
+                                extra_buffer.push('"');
+                                extra_buffer.push_str(clean_json_str(name, &mut clean_buffer));
+                                extra_buffer.push_str("\":");
+                                let _ = write_json_f64(&mut extra_buffer, *value);
+                            }
                         }
                     }
-                    TagType::Complete(t, d) => {
-                        type_tag = "X"; 
-                        tag = t;
-                        extra_buffer = format!(",\"dur\":{}", d);
+                    extra_buffer.push('}');
+                }
+                TagType::AsyncBegin(ref t, id) => {
+                    type_tag = "b";
+                    tag = thread_records.tag_name(t);
+                    extra_buffer = format!(",\"id\":{}", id);
+                }
+                TagType::AsyncEnd(ref t, id) => {
+                    type_tag = "e";
+                    tag = thread_records.tag_name(t);
+                    extra_buffer = format!(",\"id\":{}", id);
+                }
+                TagType::Flow(phase, id) => {
+                    tag = "flow";
+                    type_tag = match phase {
+                        FlowPhase::Start => "s",
+                        FlowPhase::Step => "t",
+                        FlowPhase::End => "f"
+                    };
+                    category = category.or(Some("flow"));
+                    extra_buffer = format!(",\"id\":{}", id);
+                    if phase == FlowPhase::End {
+                        // Bind to the enclosing scope rather than the next one
+                        extra_buffer.push_str(",\"bp\":\"e\"");
                     }
-                    TagType::Allocate(a) => {
-                        type_tag = "O"; 
-                        tag = "Allocate";
-                        extra_buffer = format!(",\"id\":0,\"args\":{{\"snapshot\":{{\"amount\":{}}}}}", a);
-                    }                                        
-                    TagType::Deallocate(a) => {
-                        type_tag = "O"; 
-                        tag = "Deallocate";
-                        extra_buffer = format!(",\"id\":1,\"args\":{{\"snapshot\":{{\"amount\":{}}}}}", a);                        
-                    }                                        
                 }
+                TagType::Instant(ref t, scope) => {
+                    type_tag = "i";
+                    tag = thread_records.tag_name(t);
+                    extra_buffer.push_str(match scope {
+                        InstantScope::Thread => ",\"s\":\"t\"",
+                        InstantScope::Process => ",\"s\":\"p\"",
+                        InstantScope::Global => ",\"s\":\"g\""
+                    });
+                }
+                TagType::Frame(frame) => {
+                    // Drawn as a line across all threads
+                    type_tag = "i";
+                    tag = "Frame";
+                    extra_buffer = format!(",\"s\":\"g\",\"args\":{{\"frame\":{}}}", frame);
+                }
+            }
+
+            if !first
+            {
+                w.write_all(b",\n")?;
+            }
+            first = false;
+
+            category_buffer.clear();
+            if let Some(category) = category {
+                category_buffer.push_str(",\"cat\":\"");
+                category_buffer.push_str(clean_json_str(category, &mut clean_buffer));
+                category_buffer.push('"');
+            }
+
+            // Ensure escaped json is written
+            let tag = clean_json_str(tag, &mut clean_buffer);
+
+            // Get the time from the start of the session
+            let tag_time = Micros(time_origin + profile.stopwatch.get_nanoseconds(&profile.start_time, &entry.time));
+
+            // Format the string
+            write!(w, "{{\"name\":\"{}\",\"ph\":\"{}\"{},\"ts\":{},\"pid\":{},\"tid\":{}{}}}",
+                tag, type_tag, category_buffer, tag_time, pid, stack.index, extra_buffer)?;
+        }
+
+        // Each frame runs from its boundary to the next one
+        let mut stats = ProfileStats::default();
+        for (frame_bounds, tags) in frame_starts.windows(2).zip(frame_tags) {
+            let (frame, start_time) = frame_bounds[0];
+            let mut tags : Vec<(String, Duration)> = tags.into_iter().map(|(tag, time)| (tag.to_string(), to_duration(time))).collect();
+            tags.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            stats.frames.push(FrameStats {
+                frame,
+                start : to_duration(profile.stopwatch.get_nanoseconds(&profile.start_time, &start_time)),
+                duration : to_duration(profile.stopwatch.get_nanoseconds(&start_time, &frame_bounds[1].1)),
+                tags
+            });
+        }
+
+        // Mark where each thread stopped recording due to a full buffer
+        for (buffer_index, (buffer, thread_records)) in threads.iter().enumerate() {
+            let dropped = thread_records.dropped;
+            stats.threads.push(ThreadStats {
+                thread_id : buffer.thread_id,
+                name : buffer.thread_name.clone(),
+                recorded : thread_records.records.len(),
+                overwritten : thread_records.written - thread_records.records.len(),
+                dropped
+            });
+
+            if let Some(overflow_time) = thread_records.overflow_time {
+                let new_id = thread_stack.len();
+                let stack = thread_stack.entry(buffer_index).or_insert(Tags { index : new_id, tags : vec!()});
 
                 if !first
                 {
-                    w.write(b",\n")?;
+                    w.write_all(b",\n")?;
                 }
                 first = false;
 
-                // Ensure escaped json is written
-                let tag = clean_json_str(tag, &mut clean_buffer);
+                let tag_time = Micros(time_origin + profile.stopwatch.get_nanoseconds(&profile.start_time, &overflow_time));
+                write!(w, "{{\"name\":\"Records dropped\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{},\"pid\":{},\"tid\":{},\"args\":{{",
+                    tag_time, pid, stack.index)?;
+                dropped.write_json(w, "")?;
+                w.write_all(b"}}")?;
+            }
+        }
 
-                // Get the microsecond count
-                let tag_time = profile.stopwatch.get_milliseconds(&profile.start_time, &entry.time);
+        // Write thread names, sorting the threads by the time that they appear in the profile
+        for (buffer_index, (buffer, _)) in threads.iter().enumerate() {
+            let index = match thread_stack.get(&buffer_index) {
+                Some(stack) => stack.index,
+                None => continue
+            };
 
-                // Format the string
-                write!(w, "{{\"name\":\"{}\",\"ph\":\"{}\",\"ts\":{},\"tid\":0,\"pid\":{}{}}}",
-                    tag, type_tag, tag_time, stack.index, extra_buffer)?;
+            let thread_name = match buffer.thread_name {
+                Some(ref name) => clean_json_str(name, &mut clean_buffer).to_string(),
+                None => format!("Thread {}", buffer.thread_id)
+            };
+
+            if !first
+            {
+                w.write_all(b",\n")?;
             }
-            w.write(b"\n]\n}\n")?;
-            return Ok(());
+            first = false;
+
+            writeln!(w, "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"{}\"}}}},", pid, index, thread_name)?;
+            write!(w, "{{\"name\":\"thread_sort_index\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"sort_index\":{}}}}}", pid, index, index)?;
         }
-        return Err(io::Error::from(io::ErrorKind::InvalidData));
-/*
 
-// Write thread "names"
-if (!first)
-{
-for (auto& t : threadStack)
-{
-    char indexString[64];
-    snprintf(indexString, sizeof(indexString), "%d", t.second.m_index);
+        // Write the process name and labels
+        if let Some(ref process_name) = profile.process_name {
+            if !first
+            {
+                w.write_all(b",\n")?;
+            }
+            first = false;
+
+            let process_name = clean_json_str(process_name, &mut clean_buffer);
+            write!(w, "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"tid\":0,\"args\":{{\"name\":\"{}\"}}}}", pid, process_name)?;
+        }
+        if !profile.process_labels.is_empty() {
+            if !first
+            {
+                w.write_all(b",\n")?;
+            }
 
-    // Sort thread listing by the time that they appear in the profile (tool sorts by name)
-    char indexSpaceString[64];
-    snprintf(indexSpaceString, sizeof(indexSpaceString), "%02d", t.second.m_index);
+            let process_labels = profile.process_labels.join(",");
+            let process_labels = clean_json_str(&process_labels, &mut clean_buffer);
+            write!(w, "{{\"name\":\"process_labels\",\"ph\":\"M\",\"pid\":{},\"tid\":0,\"args\":{{\"labels\":\"{}\"}}}}", pid, process_labels)?;
+        }
 
-    // Ensure a clean json string
-    std::stringstream ss;
-    ss << t.first;
-    std::string threadName = ss.str();
-    CleanJsonStr(threadName);
+        // Timestamps are fractional microseconds, so the viewer can show nanoseconds
+        write!(w, "\n],\n\"displayTimeUnit\":\"ns\",\n\"otherData\":{{")?;
 
-    o_outStream <<
-    ",\n{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":" << indexString <<
-    ",\"args\":{\"name\":\"Thread" << indexSpaceString << "_" << threadName << "\"}}";
-}
-}
-*/
+        // Write the wall clock time at the start of the session, where ts is 0 unless written from the epoch
+        write!(w, "\"start_unix_time_ns\":{},\"start_utc\":\"{}\",\"epoch_timestamps\":{},",
+            profile.start_unix_nanos, Utc(profile.start_unix_nanos), profile.epoch_timestamps)?;
 
-    }          
+        // Write the dropped record totals as trace metadata
+        let dropped = stats.dropped();
+        write!(w, "\"dropped_records\":{},", dropped.total())?;
+        dropped.write_json(w, "dropped_")?;
+        w.write_all(b"}\n}\n")?;
+        Ok(stats)
+    }
 
     fn get_profile_mutex() -> &'static mut sys::ReentrantMutex<ProfileData> {
         static INIT : Once = Once::new();
@@ -539,12 +2896,21 @@ for (auto& t : threadStack)
 
         unsafe {
             INIT.call_once(|| {
-                GPROFILE = Option::Some(sys::ReentrantMutex::new(ProfileData::new()));
+                *std::ptr::addr_of_mut!(GPROFILE) = Option::Some(sys::ReentrantMutex::new(ProfileData::new()));
             });
-            GPROFILE.as_mut().unwrap_or_else(|| {std::hint::unreachable_unchecked()})
+            (*std::ptr::addr_of_mut!(GPROFILE)).as_mut().unwrap_or_else(|| {std::hint::unreachable_unchecked()})
         }  
     }
 
+    // Locks the profiler for starting or writing a session. Fails while the thread is recording, as its
+    // buffer is locked until the record is added.
+    fn lock_profile() -> Result<sys::MutexGuard<'static, ProfileData>, ProfilerError> {
+        if RECORDING.try_with(|r| r.get()).unwrap_or(false) {
+            return Err(ProfilerError::LockBusy);
+        }
+        get_profile().map_err(|_| ProfilerError::LockBusy)
+    }
+
     fn get_profile() -> Result<sys::MutexGuard<'static, ProfileData>, ()> {
         sys::MutexGuard::new(get_profile_mutex())
     }
@@ -0,0 +1,127 @@
+// Tests of the #[profile] attribute, which must keep the behavior of the function with profiling on or off
+
+use atto_profiler::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::MutexGuard;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+mod common;
+use common::{finish, lock_profiler};
+
+fn start() -> MutexGuard<'static, ()> {
+    let lock = lock_profiler();
+    profile_start!(100).unwrap();
+    lock
+}
+
+// Checks the trace holds the event, or that nothing was written when profiling is compiled out
+fn assert_traced(trace : &str, event : &str, count : usize) {
+    if cfg!(feature = "profiling") {
+        assert_eq!(trace.matches(event).count(), count, "{} in {}", event, trace);
+    }
+    else {
+        assert!(trace.is_empty());
+    }
+}
+
+#[profile]
+fn default_name() -> u32 {
+    1
+}
+
+#[profile("custom", category = "attribute")]
+fn custom_name() -> u32 {
+    2
+}
+
+#[profile(name = "early", category = "attribute")]
+fn early_return(value : u32) -> u32 {
+    if value > 10 {
+        return 10;
+    }
+    value
+}
+
+#[profile]
+async fn parse_number(text : &str) -> Result<u32, std::num::ParseIntError> {
+    let value : u32 = text.parse()?;
+    Ok(value + 1)
+}
+
+// Pending on the first poll, so the future awaiting it is polled twice
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[profile]
+async fn yields_once() -> u32 {
+    YieldOnce(false).await;
+    3
+}
+
+// Polls the future on the calling thread until it completes
+fn block_on<F : Future>(future : F) -> F::Output {
+    fn clone(_ : *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    fn noop(_ : *const ()) {}
+    static VTABLE : RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    let waker = unsafe { Waker::from_raw(clone(std::ptr::null())) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+#[test]
+fn names_and_categories() {
+    let _lock = start();
+    assert_eq!(default_name(), 1);
+    assert_eq!(custom_name(), 2);
+    let trace = finish();
+    assert_traced(&trace, r#""name":"default_name","ph":"X","ts""#, 1);
+    assert_traced(&trace, r#""name":"custom","ph":"X","cat":"attribute""#, 1);
+}
+
+#[test]
+fn early_return_ends_scope() {
+    let _lock = start();
+    assert_eq!(early_return(50), 10);
+    assert_eq!(early_return(5), 5);
+    let trace = finish();
+    assert_traced(&trace, r#""name":"early","ph":"X","cat":"attribute""#, 2);
+    assert_traced(&trace, r#""ph":"B""#, 0);
+}
+
+#[test]
+fn async_fn_with_question_mark() {
+    let _lock = start();
+    assert_eq!(block_on(parse_number("41")), Ok(42));
+    assert!(block_on(parse_number("x")).is_err());
+    let trace = finish();
+    assert_traced(&trace, r#""name":"parse_number","ph":"X""#, 2);
+}
+
+#[test]
+fn async_fn_is_profiled_per_poll() {
+    let _lock = start();
+    assert_eq!(block_on(yields_once()), 3);
+    let trace = finish();
+    assert_traced(&trace, r#""name":"yields_once","ph":"X""#, 2);
+}
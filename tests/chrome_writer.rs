@@ -0,0 +1,340 @@
+// Golden output tests of the Chrome trace writer, timed with a mock clock
+#![cfg(feature = "profiling")]
+
+use atto_profiler::profiler::internal::{set_clock_source, set_epoch_timestamps, MockClock};
+use atto_profiler::*;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+mod common;
+use common::lock_profiler;
+
+static CLOCK : MockClock = MockClock::new();
+
+fn lock_mock_clock() -> MutexGuard<'static, ()> {
+    let lock = lock_profiler();
+    set_clock_source(Some(&CLOCK));
+    set_epoch_timestamps(false);
+    CLOCK.set(Duration::from_micros(100));
+    lock
+}
+
+fn start_mock_session(record_count : usize) -> MutexGuard<'static, ()> {
+    let lock = lock_mock_clock();
+    profile_start!(record_count).unwrap();
+    lock
+}
+
+// Fills in the process id of the expected trace
+fn golden(expected : &str) -> String {
+    expected.replace("{pid}", &std::process::id().to_string())
+}
+
+// The session starts 100us after the epoch of the mock clock
+const OTHER_DATA : &str = r#""otherData":{"start_unix_time_ns":100000,"start_utc":"1970-01-01T00:00:00.000100000Z","epoch_timestamps":false,"dropped_records":0,"dropped_begin":0,"dropped_end":0,"dropped_complete":0,"dropped_alloc":0,"dropped_instant":0,"dropped_counter":0,"dropped_async_span":0,"dropped_flow":0,"dropped_frame":0}"#;
+
+#[test]
+fn scopes_and_events() {
+    let _lock = start_mock_session(16);
+    CLOCK.advance(Duration::from_nanos(1500));
+    {
+        profile_scope!("outer", size = 3);
+        CLOCK.advance(Duration::from_nanos(250));
+        profile_instant!("mark", process);
+        profile_begin!("inner");
+        CLOCK.advance(Duration::from_micros(2));
+        profile_end!();
+        profile_counter!(cat: "memory", "heap", used = 10, free = 2.5);
+    }
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"outer","ph":"X","ts":1.500,"pid":{pid},"tid":0,"dur":2.250,"args":{"size":3}},
+{"name":"mark","ph":"i","ts":1.750,"pid":{pid},"tid":0,"s":"p"},
+{"name":"inner","ph":"B","ts":1.750,"pid":{pid},"tid":0},
+{"name":"inner","ph":"E","ts":3.750,"pid":{pid},"tid":0},
+{"name":"heap","ph":"C","cat":"memory","ts":3.750,"pid":{pid},"tid":0,"args":{"used":10,"free":2.5}},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"scopes_and_events"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+}
+
+#[test]
+fn frame_times() {
+    let _lock = start_mock_session(16);
+    for frame_micros in [10, 30, 20] {
+        profile_frame!();
+        profile_scope!("update");
+        CLOCK.advance(Duration::from_micros(frame_micros));
+    }
+    profile_frame!();
+
+    let mut out = vec![];
+    let stats = profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"Frame","ph":"i","ts":0.000,"pid":{pid},"tid":0,"s":"g","args":{"frame":0}},
+{"name":"update","ph":"X","ts":0.000,"pid":{pid},"tid":0,"dur":10.000},
+{"name":"Frame","ph":"i","ts":10.000,"pid":{pid},"tid":0,"s":"g","args":{"frame":1}},
+{"name":"update","ph":"X","ts":10.000,"pid":{pid},"tid":0,"dur":30.000},
+{"name":"Frame","ph":"i","ts":40.000,"pid":{pid},"tid":0,"s":"g","args":{"frame":2}},
+{"name":"update","ph":"X","ts":40.000,"pid":{pid},"tid":0,"dur":20.000},
+{"name":"Frame","ph":"i","ts":60.000,"pid":{pid},"tid":0,"s":"g","args":{"frame":3}},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"frame_times"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+
+    let frame_time = stats.frame_time().unwrap();
+    assert_eq!(frame_time.count, 3);
+    assert_eq!(frame_time.min, Duration::from_micros(10));
+    assert_eq!(frame_time.avg, Duration::from_micros(20));
+    assert_eq!(frame_time.max, Duration::from_micros(30));
+    assert_eq!(frame_time.p99, Duration::from_micros(30));
+    assert_eq!(stats.frames[1].start, Duration::from_micros(10));
+    assert_eq!(stats.frames[1].tags, vec![("update".to_string(), Duration::from_micros(30))]);
+}
+
+#[test]
+fn frame_times_split_scopes() {
+    let _lock = start_mock_session(16);
+    profile_frame!();
+    CLOCK.advance(Duration::from_micros(5));
+    profile_begin!("load");
+    CLOCK.advance(Duration::from_micros(5));
+    profile_frame!();
+    CLOCK.advance(Duration::from_micros(10));
+    profile_frame!();
+    CLOCK.advance(Duration::from_micros(2));
+    profile_end!();
+    profile_frame!();
+
+    // The time of a tag spanning frames is split across them
+    let stats = profile_finish!(&mut std::io::sink()).unwrap();
+    assert_eq!(stats.frames.len(), 3);
+    assert_eq!(stats.frames[0].tags, vec![("load".to_string(), Duration::from_micros(5))]);
+    assert_eq!(stats.frames[1].tags, vec![("load".to_string(), Duration::from_micros(10))]);
+    assert_eq!(stats.frames[2].tags, vec![("load".to_string(), Duration::from_micros(2))]);
+    for frame in stats.frames.iter() {
+        assert!(frame.tags.iter().map(|(_, time)| *time).sum::<Duration>() <= frame.duration);
+    }
+}
+
+#[test]
+fn dropped_records() {
+    let _lock = start_mock_session(1);
+    profile_instant!("kept");
+    CLOCK.advance(Duration::from_nanos(5));
+    profile_instant!("dropped");
+    profile_begin!("dropped");
+
+    let mut out = vec![];
+    let stats = profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(r#"{"traceEvents":[
+{"name":"kept","ph":"i","ts":0.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"Records dropped","ph":"i","s":"t","ts":0.005,"pid":{pid},"tid":0,"args":{"begin":1,"end":0,"complete":0,"alloc":0,"instant":1,"counter":0,"async_span":0,"flow":0,"frame":0}},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"dropped_records"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}}
+],
+"displayTimeUnit":"ns",
+"otherData":{"start_unix_time_ns":100000,"start_utc":"1970-01-01T00:00:00.000100000Z","epoch_timestamps":false,"dropped_records":2,"dropped_begin":1,"dropped_end":0,"dropped_complete":0,"dropped_alloc":0,"dropped_instant":1,"dropped_counter":0,"dropped_async_span":0,"dropped_flow":0,"dropped_frame":0}
+}
+"#));
+    assert_eq!(stats.dropped().total(), 2);
+}
+
+#[test]
+fn epoch_timestamps() {
+    let _lock = start_mock_session(16);
+    set_epoch_timestamps(true);
+    CLOCK.advance(Duration::from_nanos(20));
+    profile_instant!("mark");
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    assert!(trace.contains(&golden(r#"{"name":"mark","ph":"i","ts":100.020,"pid":{pid},"tid":0,"s":"t"}"#)));
+    assert!(trace.contains(r#""start_unix_time_ns":100000,"start_utc":"1970-01-01T00:00:00.000100000Z","epoch_timestamps":true,"#));
+}
+
+#[test]
+fn wall_clock_anchor() {
+    let _lock = start_mock_session(16);
+    profile_finish!(&mut std::io::sink()).unwrap();
+
+    CLOCK.set(Duration::new(1_709_210_096, 789_000_000));
+    profile_start!(16).unwrap();
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    assert!(trace.contains(r#""start_unix_time_ns":1709210096789000000,"start_utc":"2024-02-29T12:34:56.789000000Z","#));
+}
+
+#[test]
+fn flight_recorder_wraps() {
+    let _lock = lock_mock_clock();
+    profile_start_flight_recorder!(3).unwrap();
+    for tag in ["a", "b", "c", "d", "e"] {
+        profile_instant!(tag);
+        CLOCK.advance(Duration::from_micros(1));
+    }
+
+    // Dumping keeps the latest records, oldest first, without stopping the session
+    let mut out = vec![];
+    let stats = profile_dump!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"c","ph":"i","ts":2.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"d","ph":"i","ts":3.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"e","ph":"i","ts":4.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"flight_recorder_wraps"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+    assert_eq!(stats.threads[0].recorded, 3);
+    assert_eq!(stats.threads[0].overwritten, 2);
+
+    profile_instant!("f");
+    let mut out = vec![];
+    let stats = profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"d","ph":"i","ts":3.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"e","ph":"i","ts":4.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"f","ph":"i","ts":5.000,"pid":{pid},"tid":0,"s":"t"},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"flight_recorder_wraps"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+    assert_eq!(stats.threads[0].recorded, 3);
+    assert_eq!(stats.threads[0].overwritten, 3);
+    assert_eq!(stats.dropped().total(), 0);
+}
+
+fn profiled_function() {
+    profile_function!();
+    CLOCK.advance(Duration::from_micros(3));
+}
+
+fn profiled_function_segment() {
+    profile_function!(1);
+}
+
+#[test]
+fn function_scopes() {
+    let _lock = start_mock_session(16);
+    profiled_function();
+    profiled_function_segment();
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    assert!(trace.contains(&golden(r#"{"name":"chrome_writer::profiled_function","ph":"X","ts":0.000,"pid":{pid},"tid":0,"dur":3.000},
+{"name":"profiled_function_segment","ph":"X","ts":3.000,"pid":{pid},"tid":0,"dur":0.000},"#)));
+}
+
+#[test]
+fn flight_recorder_dynamic_names() {
+    let _lock = lock_mock_clock();
+    profile_start_flight_recorder!(2).unwrap();
+    // The names of overwritten records are freed and their ids reused by later names
+    for entity in [0, 1, 0, 2, 3, 2] {
+        profile_scope_dynamic!(format!("entity {}", entity));
+        CLOCK.advance(Duration::from_micros(1));
+    }
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"entity 3","ph":"X","ts":4.000,"pid":{pid},"tid":0,"dur":1.000},
+{"name":"entity 2","ph":"X","ts":5.000,"pid":{pid},"tid":0,"dur":1.000},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"flight_recorder_dynamic_names"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+}
+
+#[test]
+fn async_span_across_threads() {
+    let _lock = start_mock_session(16);
+    CLOCK.advance(Duration::from_micros(1));
+    profile_async_begin!(cat: "load", "texture", 7);
+    profile_async_begin!(cat: "load", "texture", 8);
+    std::thread::Builder::new().name("loader".to_string()).spawn(|| {
+        CLOCK.advance(Duration::from_micros(2));
+        profile_async_end!(cat: "load", "texture", 7);
+    }).unwrap().join().unwrap();
+    CLOCK.advance(Duration::from_micros(1));
+    profile_async_end!(cat: "load", "texture", 8);
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"texture","ph":"b","cat":"load","ts":1.000,"pid":{pid},"tid":0,"id":7},
+{"name":"texture","ph":"b","cat":"load","ts":1.000,"pid":{pid},"tid":0,"id":8},
+{"name":"texture","ph":"e","cat":"load","ts":3.000,"pid":{pid},"tid":1,"id":7},
+{"name":"texture","ph":"e","cat":"load","ts":4.000,"pid":{pid},"tid":0,"id":8},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"async_span_across_threads"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":1,"args":{"name":"loader"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":1,"args":{"sort_index":1}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+}
+
+#[test]
+fn flow_across_threads() {
+    let _lock = start_mock_session(16);
+    {
+        profile_scope!(cat: "jobs", "submit");
+        profile_flow_start!(cat: "jobs", 5);
+        CLOCK.advance(Duration::from_micros(1));
+    }
+    std::thread::Builder::new().name("worker".to_string()).spawn(|| {
+        profile_scope!(cat: "jobs", "run");
+        profile_flow_step!(cat: "jobs", 5);
+        CLOCK.advance(Duration::from_micros(2));
+    }).unwrap().join().unwrap();
+    {
+        profile_scope!(cat: "jobs", "complete");
+        profile_flow_end!(cat: "jobs", 5);
+        CLOCK.advance(Duration::from_micros(1));
+    }
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), golden(&(r#"{"traceEvents":[
+{"name":"submit","ph":"X","cat":"jobs","ts":0.000,"pid":{pid},"tid":0,"dur":1.000},
+{"name":"flow","ph":"s","cat":"jobs","ts":0.000,"pid":{pid},"tid":0,"id":5},
+{"name":"run","ph":"X","cat":"jobs","ts":1.000,"pid":{pid},"tid":1,"dur":2.000},
+{"name":"flow","ph":"t","cat":"jobs","ts":1.000,"pid":{pid},"tid":1,"id":5},
+{"name":"complete","ph":"X","cat":"jobs","ts":3.000,"pid":{pid},"tid":0,"dur":1.000},
+{"name":"flow","ph":"f","cat":"jobs","ts":3.000,"pid":{pid},"tid":0,"id":5,"bp":"e"},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":0,"args":{"name":"flow_across_threads"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":0,"args":{"sort_index":0}},
+{"name":"thread_name","ph":"M","pid":{pid},"tid":1,"args":{"name":"worker"}},
+{"name":"thread_sort_index","ph":"M","pid":{pid},"tid":1,"args":{"sort_index":1}}
+],
+"displayTimeUnit":"ns",
+"#.to_string() + OTHER_DATA + "\n}\n")));
+}
+
+#[test]
+fn escaped_strings() {
+    let _lock = start_mock_session(16);
+    {
+        profile_scope_dynamic!("line1\nline2\tTab\u{1}\"quoted\"");
+        profile_scope!("open", path = "C:\\x\ny");
+    }
+
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    assert!(trace.contains(&golden(r#"{"name":"line1\nline2\tTab\u0001\"quoted\"","ph":"X","ts":0.000,"pid":{pid},"tid":0,"dur":0.000},
+{"name":"open","ph":"X","ts":0.000,"pid":{pid},"tid":0,"dur":0.000,"args":{"path":"C:\\x\ny"}},"#)), "{}", trace);
+}
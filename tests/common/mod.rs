@@ -0,0 +1,19 @@
+// Helpers shared by the integration tests, each test file using some of them
+#![allow(dead_code)]
+
+use atto_profiler::*;
+use std::sync::{Mutex, MutexGuard};
+
+// The profiler is global, so the tests take turns using it
+static PROFILER : Mutex<()> = Mutex::new(());
+
+pub fn lock_profiler() -> MutexGuard<'static, ()> {
+    PROFILER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Finishes the session, returning the trace (empty when profiling is compiled out)
+pub fn finish() -> String {
+    let mut out = vec![];
+    profile_finish!(&mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
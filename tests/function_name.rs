@@ -0,0 +1,45 @@
+// Tests of the function path trimming used by profile_function!
+
+use atto_profiler::profiler::internal::function_name;
+
+#[test]
+fn strips_marker_function() {
+    assert_eq!(function_name("app::render::draw::f", 0), "app::render::draw");
+    assert_eq!(function_name("draw::f", 0), "draw");
+}
+
+#[test]
+fn strips_closures() {
+    assert_eq!(function_name("app::render::draw::{{closure}}::f", 0), "app::render::draw");
+    assert_eq!(function_name("app::render::draw::{{closure}}::{{closure}}::f", 1), "draw");
+}
+
+#[test]
+fn keeps_last_segments() {
+    assert_eq!(function_name("app::render::draw::f", 1), "draw");
+    assert_eq!(function_name("app::render::draw::f", 2), "render::draw");
+    assert_eq!(function_name("app::render::draw::f", 3), "app::render::draw");
+}
+
+#[test]
+fn segments_beyond_path_depth() {
+    assert_eq!(function_name("app::render::draw::f", 4), "app::render::draw");
+    assert_eq!(function_name("draw::f", 10), "draw");
+}
+
+#[test]
+fn trait_methods() {
+    let name = "<app::Sprite as app::render::Draw>::draw::f";
+    assert_eq!(function_name(name, 0), "<app::Sprite as app::render::Draw>::draw");
+    assert_eq!(function_name(name, 1), "draw");
+    assert_eq!(function_name(name, 2), "<app::Sprite as app::render::Draw>::draw");
+    assert_eq!(function_name(name, 3), "<app::Sprite as app::render::Draw>::draw");
+}
+
+#[test]
+fn nested_generics() {
+    let name = "app::Batch<alloc::vec::Vec<core::option::Option<u32>>>::flush::{{closure}}::f";
+    assert_eq!(function_name(name, 1), "flush");
+    assert_eq!(function_name(name, 2), "Batch<alloc::vec::Vec<core::option::Option<u32>>>::flush");
+    assert_eq!(function_name(name, 3), "app::Batch<alloc::vec::Vec<core::option::Option<u32>>>::flush");
+}
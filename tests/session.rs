@@ -0,0 +1,198 @@
+// Tests of scopes that are closed in a different profile session or thread than the one they began in
+#![cfg(feature = "profiling")]
+
+use atto_profiler::profiler::internal::{MemTrackAllocator, MockClock, ProfileConfig, ProfileScope, ProfilerError};
+use atto_profiler::*;
+use std::fmt;
+
+mod common;
+use common::{finish, lock_profiler};
+
+#[test]
+fn scope_in_session_is_complete() {
+    let _lock = lock_profiler();
+    profile_start!(100).unwrap();
+    {
+        profile_scope!("inner");
+    }
+    let trace = finish();
+    assert!(trace.contains(r#""name":"inner","ph":"X""#));
+    assert!(!trace.contains(r#""ph":"B""#));
+}
+
+#[test]
+fn scope_straddling_sessions_is_discarded() {
+    let _lock = lock_profiler();
+    profile_start!(100).unwrap();
+    let scope = ProfileScope::new(None, "straddle");
+    let first = finish();
+    assert!(first.contains(r#""name":"straddle","ph":"B""#));
+
+    // The begin record takes the same sequence number as the record of the earlier scope
+    profile_start!(100).unwrap();
+    profile_begin!("open");
+    drop(scope);
+    profile_end!();
+    let second = finish();
+    assert!(second.contains(r#""name":"open","ph":"B""#));
+    assert!(second.contains(r#""name":"open","ph":"E""#));
+    assert!(!second.contains(r#""name":"straddle""#));
+    assert!(!second.contains(r#""ph":"X""#));
+}
+
+#[test]
+fn scope_ending_between_sessions_is_discarded() {
+    let _lock = lock_profiler();
+    profile_start!(100).unwrap();
+    let scope = ProfileScope::new(None, "between");
+    finish();
+    drop(scope);
+
+    profile_start!(100).unwrap();
+    let trace = finish();
+    assert!(!trace.contains(r#""name":"between""#));
+}
+
+#[test]
+fn overwritten_scope_is_discarded() {
+    let _lock = lock_profiler();
+    profile_start_flight_recorder!(2).unwrap();
+    let scope = ProfileScope::new(None, "lost");
+    profile_instant!("first");
+    profile_instant!("second");
+    drop(scope);
+    let trace = finish();
+    assert!(!trace.contains(r#""name":"lost""#));
+    assert!(trace.contains(r#""name":"first""#));
+    assert!(trace.contains(r#""name":"second""#));
+}
+
+#[test]
+fn scope_dropped_on_other_thread_is_left_open() {
+    let _lock = lock_profiler();
+    profile_start_with_config!(ProfileConfig::new(100).track_memory(false)).unwrap();
+    let scope = ProfileScope::new(None, "moved");
+    std::thread::spawn(move || {
+        // The begin record takes the same sequence number as the record of the moved scope
+        profile_begin!("other_thread_open");
+        drop(scope);
+        profile_end!();
+    }).join().unwrap();
+    let trace = finish();
+    assert!(trace.contains(r#""name":"moved","ph":"B""#));
+    assert!(trace.contains(r#""name":"other_thread_open","ph":"B""#));
+    assert!(trace.contains(r#""name":"other_thread_open","ph":"E""#));
+    assert!(!trace.contains(r#""ph":"X""#));
+    assert!(!trace.contains("Unknown"));
+}
+
+struct PanicsOnDisplay;
+
+impl fmt::Display for PanicsOnDisplay {
+    fn fmt(&self, _ : &mut fmt::Formatter) -> fmt::Result {
+        panic!("display failed");
+    }
+}
+
+#[test]
+fn panicking_tag_name_keeps_recording() {
+    let _lock = lock_profiler();
+    profile_start!(100).unwrap();
+    let result = std::panic::catch_unwind(|| {
+        profile_scope!("load {}", PanicsOnDisplay);
+    });
+    assert!(result.is_err());
+    profile_instant!("after panic");
+    let trace = finish();
+    assert!(trace.contains(r#""name":"after panic""#));
+}
+
+// Formats as whether finishing the session failed as the profiler is busy
+struct FinishesOnDisplay;
+
+impl fmt::Display for FinishesOnDisplay {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let busy = matches!(profile_finish!(&mut std::io::sink()), Err(ProfilerError::LockBusy));
+        write!(f, "busy {}", busy)
+    }
+}
+
+#[test]
+fn finishing_while_recording_is_busy() {
+    let _lock = lock_profiler();
+    profile_start!(100).unwrap();
+    {
+        profile_scope!("{}", FinishesOnDisplay);
+    }
+    let trace = finish();
+    assert!(trace.contains(r#""name":"busy true""#));
+}
+
+#[test]
+fn session_misuse_errors() {
+    let _lock = lock_profiler();
+    assert!(matches!(profile_finish!(&mut vec![]), Err(ProfilerError::NotStarted)));
+    assert!(matches!(profile_start!(usize::MAX), Err(ProfilerError::Overflow)));
+
+    profile_start!(100).unwrap();
+    assert!(matches!(profile_start!(100), Err(ProfilerError::AlreadyRunning)));
+    assert!(matches!(profile_finish_to_file!("/nonexistent/dir/trace.json"), Err(ProfilerError::Io(_))));
+
+    // The session keeps recording after a failed finish
+    assert!(MemTrackAllocator::get_mem_tracking());
+    finish();
+    assert!(!MemTrackAllocator::get_mem_tracking());
+}
+
+#[test]
+fn records_are_allocated_as_needed() {
+    let _lock = lock_profiler();
+    // Far more records than could be allocated up front
+    profile_start!(isize::MAX as usize / 1024).unwrap();
+    for _ in 0..100 {
+        profile_instant!("mark");
+    }
+    let trace = finish();
+    assert_eq!(trace.matches(r#""name":"mark""#).count(), 100);
+}
+
+#[test]
+fn config_filters_threads_and_categories() {
+    let _lock = lock_profiler();
+    let config = ProfileConfig::new(100)
+        .categories(&["render"])
+        .track_memory(false)
+        .thread_filter(|name| name != Some("ignored"));
+    profile_start_with_config!(config).unwrap();
+    profile_scope!(cat: "render", "drawn");
+    profile_scope!(cat: "io", "skipped");
+    std::thread::Builder::new().name("ignored".to_string()).spawn(|| {
+        profile_instant!("ignored thread");
+    }).unwrap().join().unwrap();
+    std::thread::Builder::new().name("recorded".to_string()).spawn(|| {
+        profile_instant!("recorded thread");
+    }).unwrap().join().unwrap();
+    let trace = finish();
+    assert!(trace.contains(r#""name":"drawn","ph":"B","cat":"render""#));
+    assert!(!trace.contains(r#""name":"skipped""#));
+    assert!(trace.contains(r#""name":"recorded thread""#));
+    assert!(!trace.contains(r#""name":"ignored thread""#));
+    assert!(!trace.contains(r#""name":"Allocate""#));
+
+    // The category filter of the config only applies to its session
+    profile_start!(100).unwrap();
+    profile_instant!(cat: "io", "recorded later");
+    let trace = finish();
+    assert!(trace.contains(r#""name":"recorded later""#));
+}
+
+#[test]
+fn config_clock_only_applies_to_its_session() {
+    static CLOCK : MockClock = MockClock::new();
+    let _lock = lock_profiler();
+    profile_start_with_config!(ProfileConfig::new(100).clock_source(Some(&CLOCK))).unwrap();
+    assert!(finish().contains(r#""start_unix_time_ns":0,"#));
+
+    profile_start!(100).unwrap();
+    assert!(!finish().contains(r#""start_unix_time_ns":0,"#));
+}